@@ -0,0 +1,62 @@
+//! Throughput benchmarks for Pico encoding and decoding.
+//!
+//! Run with `cargo bench`.  Data is held in memory, so these measure the
+//! cost of encryption and the library overhead rather than disk speed.
+//! The ciphers are also measured on their own, to separate the two.
+extern crate pico;
+
+use std::io::{copy, sink, Cursor};
+use std::time::Instant;
+use pico::{CipherKind, PicoReader};
+
+/// Number of bytes of data to encode and decode.
+const DATA_LEN: usize = 256 * 1024 * 1024;
+
+/// Number of times to repeat each measurement.  The best time is reported.
+const ROUNDS: usize = 5;
+
+/// Run the given operation several times and report its best throughput.
+fn measure<F: FnMut()>(name: &str, mut operation: F) {
+    let mut best = None;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        operation();
+        let elapsed = start.elapsed().as_secs_f64();
+        best = Some(best.map_or(elapsed, |value: f64| value.min(elapsed)));
+    }
+    let seconds = best.unwrap();
+    println!(
+        "{:<24} {:>8.3} s {:>8.2} GB/s",
+        name,
+        seconds,
+        DATA_LEN as f64 / seconds / 1e9
+    );
+}
+
+fn main() {
+    let data: Vec<u8> = (0..DATA_LEN).map(|index| (index * 31 % 251) as u8).collect();
+    for key_len in &[4u16, 16, 257] {
+        let key = pico::gen_random_key(*key_len);
+        println!("Key length {}:", key_len);
+        let mut buffer = data.clone();
+        measure("  xor cipher", || {
+            CipherKind::XOR.apply(&key, 0, &mut buffer);
+        });
+        let mut encoded = Vec::new();
+        measure("  encode_bytes", || {
+            encoded = pico::encode_bytes(&data, key.clone(), b"").unwrap();
+        });
+        measure("  decode_bytes", || {
+            pico::decode_bytes(&encoded).unwrap();
+        });
+        measure("  PicoReader to sink", || {
+            let mut reader = PicoReader::open(Cursor::new(&encoded[..])).unwrap();
+            copy(&mut reader, &mut sink()).unwrap();
+        });
+    }
+    let key = pico::gen_random_key(16);
+    let mut buffer = data.clone();
+    measure("ChaCha20 cipher", || {
+        CipherKind::CHACHA20.apply(&key, 0, &mut buffer);
+    });
+}
@@ -0,0 +1,20 @@
+//! Open arbitrary bytes as a Pico-encoded file, and read everything the
+//! header describes.  Run with `cargo fuzz run open`.
+#![no_main]
+
+use std::io::Cursor;
+use libfuzzer_sys::fuzz_target;
+use pico::PicoOpenOptions;
+
+fuzz_target!(|data: &[u8]| {
+    let mut options = PicoOpenOptions::new();
+    options.max_metadata_length(1 << 16);
+    if let Ok(mut pico) = options.open(Cursor::new(data)) {
+        let mut output = Vec::new();
+        pico.dump_header(&mut output, &pico::HeaderFormat::JSON);
+        let _ = pico.read_metadata_map();
+        let mut buffer = [0u8; 256];
+        let _ = pico.get(0, &mut buffer);
+        let _ = pico.verify();
+    }
+});
@@ -0,0 +1,293 @@
+//! Create Pico-encoded files with validated options.
+//!
+//! `Pico::new` covers the common case.  A `PicoBuilder` also lets you pick
+//! the version of the encoding, the hashes to store, the cipher, and the
+//! initial metadata, and generates a random key if you do not give one.
+//! Every option is checked before anything is written to the file.
+//!
+//! ```
+//! use std::io::Cursor;
+//! use pico::{CipherKind, HashAlgorithm, PicoBuilder};
+//!
+//! let mut encoded = Cursor::new(Vec::new());
+//! let mut pico = PicoBuilder::new()
+//!     .random_key(32)
+//!     .reserve(64)
+//!     .metadata(b"Newcomb".to_vec())
+//!     .hashes(&[HashAlgorithm::SHA1, HashAlgorithm::SHA256])
+//!     .cipher(CipherKind::CHACHA20)
+//!     .create(&mut encoded)
+//!     .unwrap();
+//! pico.put(0, &mut b"Martindale".to_vec()).unwrap();
+//! pico.flush().unwrap();
+//! assert_eq!(pico.get_key().len(), 32);
+//! ```
+
+use std::io::{Read, Write, Seek};
+use constants::{MAJOR, MINOR};
+use crypt::CipherKind;
+use errors::{PicoError, Result};
+use header::{HashAlgorithm, PicoHeader};
+use metadata::Metadata;
+use pico::{Pico, gen_random_key};
+use provenance::Provenance;
+
+/// Length of the random key generated if no key is given.
+const DEFAULT_KEY_LEN: u16 = 16;
+
+/// Where the key comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KeySource {
+    /// Use the given key.
+    Key(Vec<u8>),
+    /// Generate a random key of the given length.
+    Random(u16),
+}
+
+/// Options for creating a new Pico-encoded file.  Set the options, and then
+/// call `create` to write the header and initial metadata.
+#[derive(Debug, Clone)]
+pub struct PicoBuilder {
+    /// Where the key comes from.
+    key: KeySource,
+    /// The number of bytes to reserve for metadata, if given.
+    reserve: Option<u32>,
+    /// The initial metadata.
+    metadata: Vec<u8>,
+    /// The major and minor version numbers of the encoding.
+    version: (u16, u16),
+    /// The hashes to store in addition to MD5.
+    hashes: Vec<HashAlgorithm>,
+    /// The cipher used to encrypt the data.
+    cipher: CipherKind,
+    /// The provenance to merge into the initial metadata, if given.
+    provenance: Option<Provenance>,
+}
+
+impl Default for PicoBuilder {
+    fn default() -> PicoBuilder {
+        PicoBuilder::new()
+    }
+}
+
+impl PicoBuilder {
+    /// Start with the default options: a random 16 byte key, no metadata,
+    /// the version of the encoding implemented by this library, only the
+    /// MD5 hash, and the xor cipher.
+    pub fn new() -> PicoBuilder {
+        PicoBuilder {
+            key: KeySource::Random(DEFAULT_KEY_LEN),
+            reserve: None,
+            metadata: Vec::new(),
+            version: (MAJOR, MINOR),
+            hashes: Vec::new(),
+            cipher: CipherKind::XOR,
+            provenance: None,
+        }
+    }
+
+    /// Use the given key.  The key must have between 1 and 65535 bytes.
+    pub fn key(&mut self, key: Vec<u8>) -> &mut PicoBuilder {
+        self.key = KeySource::Key(key);
+        self
+    }
+
+    /// Generate a random key with the given number of bytes, which cannot
+    /// be zero.
+    pub fn random_key(&mut self, length: u16) -> &mut PicoBuilder {
+        self.key = KeySource::Random(length);
+        self
+    }
+
+    /// Reserve the given number of bytes for metadata.  By default exactly
+    /// enough bytes are reserved to hold the initial metadata.
+    pub fn reserve(&mut self, md_length: u32) -> &mut PicoBuilder {
+        self.reserve = Some(md_length);
+        self
+    }
+
+    /// Store the given raw bytes as the initial metadata.
+    pub fn metadata(&mut self, metadata: Vec<u8>) -> &mut PicoBuilder {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Store the given structured metadata as the initial metadata.  If
+    /// the metadata cannot be encoded, the error is returned.
+    pub fn metadata_map(&mut self, metadata: &Metadata) -> Result<&mut PicoBuilder> {
+        self.metadata = metadata.encode()?;
+        Ok(self)
+    }
+
+    /// Use the given version of the encoding, such as 1.0 for
+    /// compatibility with older readers.
+    pub fn version(&mut self, major: u16, minor: u16) -> &mut PicoBuilder {
+        self.version = (major, minor);
+        self
+    }
+
+    /// Store the given hashes of the data.  The MD5 hash is always stored,
+    /// whether or not it is listed.  Other hashes need version 2.0 or
+    /// later, and by default are not stored, since computing them slows
+    /// every write.
+    pub fn hashes(&mut self, algorithms: &[HashAlgorithm]) -> &mut PicoBuilder {
+        self.hashes = algorithms.to_vec();
+        self
+    }
+
+    /// Encrypt the data with the given cipher.  Ciphers other than xor
+    /// need version 2.0 or later.
+    pub fn cipher(&mut self, cipher: CipherKind) -> &mut PicoBuilder {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Record the given provenance, such as from `Provenance::for_file`.
+    /// It is merged into the initial metadata as structured metadata, and
+    /// at least enough bytes are reserved to hold the result.
+    pub fn provenance(&mut self, provenance: &Provenance) -> &mut PicoBuilder {
+        self.provenance = Some(provenance.clone());
+        self
+    }
+
+    /// Check the options and build the header for the new file.  A random
+    /// key, if requested, is generated here.
+    pub fn header(&self) -> Result<PicoHeader> {
+        Ok(self.prepare()?.0)
+    }
+
+    /// Check the options, and build the header and the initial metadata
+    /// for the new file.
+    fn prepare(&self) -> Result<(PicoHeader, Vec<u8>)> {
+        let key = match self.key {
+            KeySource::Key(ref key) => key.clone(),
+            KeySource::Random(0) => return Err(PicoError::KeyError),
+            KeySource::Random(length) => gen_random_key(length),
+        };
+        PicoHeader::check_key(&key)?;
+        let reserve = self.reserve.unwrap_or(self.metadata.len() as u32);
+        if self.metadata.len() > reserve as usize {
+            return Err(PicoError::MetadataTooLarge(self.metadata.len() as u32, reserve));
+        }
+        let (metadata, reserve) = match self.provenance {
+            None => (self.metadata.clone(), reserve),
+            Some(ref provenance) => {
+                let metadata = provenance.merge(&self.metadata)?;
+                let reserve = reserve.max(metadata.len() as u32);
+                (metadata, reserve)
+            },
+        };
+        let (major, minor) = self.version;
+        let mut header = PicoHeader::new_version(major, minor, key, 0)?;
+        for &algorithm in &self.hashes {
+            header.enable_hash(algorithm)?;
+        }
+        header.set_cipher(self.cipher)?;
+
+        // The offset to the data must fit in 32 bits.
+        let md_start = header.md_start() as u64;
+        if md_start + reserve as u64 > u32::MAX as u64 {
+            let room = (u32::MAX as u64).saturating_sub(md_start) as u32;
+            return Err(PicoError::MetadataTooLarge(reserve, room));
+        }
+        header.md_length = reserve;
+        header.update_offset()?;
+        Ok((header, metadata))
+    }
+
+    /// Create a new Pico-encoded file with these options.  The header and
+    /// initial metadata are written, and the file is ready for data.
+    ///
+    /// # Arguments
+    /// * `file` - An open file for writing that must support `seek`.
+    pub fn create<T: Seek + Read + Write>(&self, file: T) -> Result<Pico<T>> {
+        let (header, metadata) = self.prepare()?;
+        let mut pico = Pico::from_header(file, header)?;
+        pico.put_metadata(0, &metadata)?;
+        Ok(pico)
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use std::io::Cursor;
+    use crypt::CipherKind;
+    use errors::PicoError;
+    use header::HashAlgorithm;
+    use super::PicoBuilder;
+
+    #[test]
+    fn builder_test_1() {
+        let header = PicoBuilder::new().header().unwrap();
+        assert_eq!(header.key.len(), 16);
+        assert_eq!(header.md_length, 0);
+        assert!(header.extensions.is_empty());
+
+        let header = PicoBuilder::new().hashes(&[HashAlgorithm::SHA256]).header().unwrap();
+        assert!(header.get_stored_hash(HashAlgorithm::SHA256).is_some());
+        assert!(header.get_stored_hash(HashAlgorithm::SHA1).is_none());
+
+        let header = PicoBuilder::new().version(1, 0).key(vec![0x55]).reserve(10).header().unwrap();
+        assert_eq!((header.major, header.key.clone(), header.md_length), (1, vec![0x55], 10));
+        assert!(header.extensions.is_empty());
+    }
+
+    #[test]
+    fn builder_test_2() {
+        match PicoBuilder::new().key(vec![]).create(Cursor::new(Vec::new())) {
+            Err(PicoError::KeyError) => (),
+            _ => panic!("Expected a key error."),
+        }
+        match PicoBuilder::new().random_key(0).header() {
+            Err(PicoError::KeyError) => (),
+            _ => panic!("Expected a key error."),
+        }
+        match PicoBuilder::new().key(vec![0; 65536]).header() {
+            Err(PicoError::KeyError) => (),
+            _ => panic!("Expected a key error."),
+        }
+        match PicoBuilder::new().metadata(b"Newcomb".to_vec()).reserve(3).header() {
+            Err(PicoError::MetadataTooLarge(7, 3)) => (),
+            _ => panic!("Expected the metadata to be too large."),
+        }
+        match PicoBuilder::new().key(vec![0x55]).reserve(u32::MAX).header() {
+            Err(PicoError::MetadataTooLarge(u32::MAX, _)) => (),
+            _ => panic!("Expected the metadata to be too large."),
+        }
+        match ::Pico::new(Cursor::new(Vec::new()), vec![0x55], u32::MAX) {
+            Err(PicoError::MetadataTooLarge(u32::MAX, _)) => (),
+            _ => panic!("Expected the metadata to be too large."),
+        }
+        match PicoBuilder::new().version(3, 0).header() {
+            Err(PicoError::BadVersion(3, 0)) => (),
+            _ => panic!("Expected a bad version."),
+        }
+        match PicoBuilder::new().version(1, 0).cipher(CipherKind::CHACHA20).header() {
+            Err(PicoError::ExtensionsNotSupported(1, 0)) => (),
+            _ => panic!("Expected extensions to be unsupported."),
+        }
+    }
+
+    #[test]
+    fn builder_test_3() {
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let mut pico = PicoBuilder::new()
+                .key(vec![0x55, 0x21])
+                .metadata(b"Newcomb".to_vec())
+                .reserve(10)
+                .hashes(&[HashAlgorithm::SHA1])
+                .cipher(CipherKind::CHACHA20)
+                .create(&mut encoded)
+                .unwrap();
+            pico.put(0, &mut b"Martindale".to_vec()).unwrap();
+            pico.flush().unwrap();
+        }
+        let (data, metadata, header) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, b"Martindale");
+        assert_eq!(metadata, b"Newcomb\0\0\0");
+        assert_eq!(header.get_cipher().unwrap(), CipherKind::CHACHA20);
+        assert!(header.get_stored_hash(HashAlgorithm::SHA1).is_some());
+        assert!(header.get_stored_hash(HashAlgorithm::SHA256).is_none());
+    }
+}
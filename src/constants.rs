@@ -4,7 +4,7 @@
 pub const MAGIC: u16 = 0x91c0;
 
 /// Major version number of supported Pico format.
-pub const MAJOR: u16 = 1;
+pub const MAJOR: u16 = 2;
 
 /// Minor version number of supported Pico format.
 pub const MINOR: u16 = 0;
@@ -31,6 +31,33 @@ pub const HASH_LEN: usize = 16;
 /// Size (in bytes) of the key length.
 pub const KEYLEN_LEN: usize = 2;
 
+/// Size (in bytes) of the extension area length.
+pub const EXTLEN_LEN: usize = 4;
+
+/// Size (in bytes) of the type of an extension entry.
+pub const EXT_TYPE_LEN: usize = 2;
+
+/// Size (in bytes) of the length of an extension entry.
+pub const EXT_LENGTH_LEN: usize = 4;
+
+/// First major version with a header extension area.
+pub const EXTENSIONS_MAJOR: u16 = 2;
+
+/// Extension type holding the SHA-1 hash of the data.
+pub const EXT_SHA1: u16 = 1;
+
+/// Extension type holding the SHA-256 hash of the data.
+pub const EXT_SHA256: u16 = 2;
+
+/// Extension type holding the identifier of the cipher used for the data.
+pub const EXT_CIPHER: u16 = 3;
+
+/// Size (in bytes) of a SHA-1 hash.
+pub const SHA1_LEN: usize = 20;
+
+/// Size (in bytes) of a SHA-256 hash.
+pub const SHA256_LEN: usize = 32;
+
 //
 // Field offsets from start of file.
 //
@@ -1,31 +1,187 @@
-//! Function to encrypt / decrypt data in the Pico file.
+//! Ciphers to encrypt / decrypt data in the Pico file.
+//!
+//! Every cipher is a stream cipher that can start at any position in the
+//! data, so that the data can be read and written in any order.  The
+//! ciphers are a fixed set, listed by `CipherKind`, since a reader must
+//! know every cipher a file might use.  The cipher used by a file is
+//! recorded in the header; files that do not record one use the original
+//! xor cipher.
 
-/// Encrypt or decrypt, in place, the given data.
+use std::convert::TryInto;
+use std::mem;
+use chacha20::ChaCha20Legacy;
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use sha2::{Digest, Sha256};
+
+/// Minimum number of bytes processed per pass over the expanded key.
+const STREAM_LEN: usize = 16384;
+
+/// The ChaCha20 key stream is split into segments of this many bytes (as
+/// a power of two), each with its own nonce, since a single key stream is
+/// limited by its 32-bit block counter.
+const SEGMENT_BITS: u32 = 37;
+
+/// A position-addressable stream cipher.  Because the key stream is
+/// xored with the data, the same operation both encrypts and decrypts.
+pub(crate) trait Cipher: Send + Sync {
+    /// Encrypt or decrypt, in place, the given data.
+    ///
+    /// # Arguments
+    /// * `position` - Zero-based position where the data will reside in the file.
+    /// * `data`     - The data to encrypt or decrypt.
+    fn apply(&self, position: usize, data: &mut [u8]);
+}
+
+/// The ciphers that can be recorded in the header.  This is a fixed
+/// choice; new ciphers are added here, along with their identifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherKind {
+    /// Xor with the repeated key.  This is the original Pico cipher, and
+    /// is used by any file that does not record a cipher.
+    XOR,
+    /// ChaCha20, keyed with the SHA-256 hash of the key.
+    CHACHA20,
+}
+
+impl CipherKind {
+    /// Get the identifier recorded in the header for this cipher.
+    pub fn id(&self) -> u16 {
+        match *self {
+            CipherKind::XOR => 0,
+            CipherKind::CHACHA20 => 1,
+        }
+    }
+
+    /// Get the cipher with the given identifier, if it is known.
+    pub fn from_id(id: u16) -> Option<CipherKind> {
+        match id {
+            0 => Some(CipherKind::XOR),
+            1 => Some(CipherKind::CHACHA20),
+            _ => None,
+        }
+    }
+
+    /// Create this cipher for the given key.  The key must not be empty.
+    pub(crate) fn cipher(&self, key: &[u8]) -> Box<dyn Cipher> {
+        match *self {
+            CipherKind::XOR => Box::new(KeyStream::new(key)),
+            CipherKind::CHACHA20 => Box::new(ChaChaStream::new(key)),
+        }
+    }
+
+    /// Encrypt or decrypt, in place, data at the given position using this
+    /// cipher with the given key.  The key must not be empty.
+    ///
+    /// # Arguments
+    /// * `key`      - The encryption key.
+    /// * `position` - Zero-based position where the data resides in the file.
+    /// * `data`     - The data to encrypt or decrypt.
+    pub fn apply(&self, key: &[u8], position: usize, data: &mut [u8]) {
+        self.cipher(key).apply(position, data);
+    }
+}
+
+/// A key expanded for fast encryption and decryption.
 ///
-/// Because Pico uses simple symmetric xor encryption, this method will
-/// both encrypt and decrypt.
+/// Because Pico uses simple symmetric xor encryption, the same operation
+/// both encrypts and decrypts.  The key byte used at a given position is
+/// `key[position % key.len()]`, so the key is repeated into a long stream
+/// once, and then data is xored against slices of that stream eight bytes
+/// at a time.
+pub struct KeyStream {
+    /// The length of the original key.
+    key_len: usize,
+    /// The key repeated a whole number of times, to at least
+    /// `STREAM_LEN + key_len` bytes.
+    stream: Vec<u8>,
+}
+
+impl KeyStream {
+    /// Expand the given key.
+    pub fn new(key: &[u8]) -> KeyStream {
+        let key_len = key.len();
+        let stream = if key_len == 0 {
+            Vec::new()
+        } else {
+            key.repeat((STREAM_LEN + key_len).div_ceil(key_len))
+        };
+        KeyStream { key_len, stream }
+    }
+}
+
+impl Cipher for KeyStream {
+    fn apply(&self, position: usize, data: &mut [u8]) {
+        if self.key_len == 0 { panic!("Zero length key."); }
+        let block = self.stream.len() - self.key_len;
+        let mut phase = position % self.key_len;
+        for chunk in data.chunks_mut(block) {
+            xor(chunk, &self.stream[phase..phase + chunk.len()]);
+            phase = (phase + chunk.len()) % self.key_len;
+        }
+    }
+}
+
+/// ChaCha20 encryption.
 ///
-/// # Arguments
-/// * `position` - Zero-based position where the data will reside in the file.
-/// * `data`     - The data to encrypt or decrypt.
-/// * `key`      - The key to use for encryption and decryption.
-pub fn crypt(position: usize, data: &mut [u8], key: &Vec<u8>) {
-    let klen = key.len() as usize;
-    if klen == 0 { panic!("Zero length key."); }
-    for index in 0..(data.len()) {
-        data[index] ^= key[(index + position) % klen];
+/// The cipher key is the SHA-256 hash of the Pico key, so keys of any
+/// length can be used.  The data is split into segments of
+/// `2^SEGMENT_BITS` bytes, and the nonce of each segment is its index.
+pub struct ChaChaStream {
+    /// The ChaCha20 key.
+    key: [u8; 32],
+}
+
+impl ChaChaStream {
+    /// Derive the cipher key from the given key.
+    pub fn new(key: &[u8]) -> ChaChaStream {
+        if key.is_empty() { panic!("Zero length key."); }
+        ChaChaStream { key: Sha256::digest(key).into() }
+    }
+}
+
+impl Cipher for ChaChaStream {
+    fn apply(&self, position: usize, data: &mut [u8]) {
+        let mut position = position as u64;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let segment = position >> SEGMENT_BITS;
+            let offset = position & ((1 << SEGMENT_BITS) - 1);
+            let count = ((1u64 << SEGMENT_BITS) - offset).min(rest.len() as u64) as usize;
+            let (chunk, later) = mem::take(&mut rest).split_at_mut(count);
+            let mut cipher = ChaCha20Legacy::new(&self.key.into(), &segment.to_be_bytes().into());
+            cipher.seek(offset);
+            cipher.apply_keystream(chunk);
+            position += count as u64;
+            rest = later;
+        }
+    }
+}
+
+/// Xor the stream into the data, eight bytes at a time.  The stream must
+/// be the same length as the data.
+fn xor(data: &mut [u8], stream: &[u8]) {
+    let mut words = data.chunks_exact_mut(8);
+    let mut keys = stream.chunks_exact(8);
+    for (word, key) in (&mut words).zip(&mut keys) {
+        let value = u64::from_ne_bytes((&*word).try_into().unwrap()) ^
+            u64::from_ne_bytes(key.try_into().unwrap());
+        word.copy_from_slice(&value.to_ne_bytes());
+    }
+    for (byte, key) in words.into_remainder().iter_mut().zip(keys.remainder()) {
+        *byte ^= *key;
     }
 }
 
 #[allow(unused_imports)]
 mod test {
-    use super::crypt;
+    use super::{Cipher, CipherKind, KeyStream};
+    use rand::{Rng, thread_rng};
 
     #[test]
     fn crypt_test_1() {
         let key = vec![0u8];
         let mut data = [18u8, 21u8];
-        crypt(0, &mut data, &key);
+        KeyStream::new(&key).apply(0, &mut data);
         assert_eq!(data, [18u8, 21u8]);
     }
 
@@ -33,7 +189,7 @@ mod test {
     fn crypt_test_2() {
         let key = vec![0u8];
         let mut data: [u8;0] = [];
-        crypt(0, &mut data, &key);
+        KeyStream::new(&key).apply(0, &mut data);
         assert_eq!(data, []);
     }
 
@@ -41,7 +197,7 @@ mod test {
     fn crypt_test_3() {
         let key = vec![0x40u8, 0x09u8];
         let mut data = [0x09u8, 0x20u8, 0x00u8, 0xe0u8];
-        crypt(0, &mut data, &key);
+        KeyStream::new(&key).apply(0, &mut data);
         assert_eq!(data, [0x49u8, 0x29u8, 0x40u8, 0xe9u8]);
     }
 
@@ -49,7 +205,64 @@ mod test {
     fn crypt_test_4() {
         let key = vec![0xaau8, 0x55u8, 0x63u8, 0xf7u8, 0x7eu8];
         let mut data = [0x9au8, 0xd4u8, 0x6cu8, 0x58u8];
-        crypt(0, &mut data, &key);
+        KeyStream::new(&key).apply(0, &mut data);
         assert_eq!(data, [0x30u8, 0x81u8, 0x0fu8, 0xafu8]);
     }
+
+    #[test]
+    fn crypt_test_5() {
+        // Compare against a byte-at-a-time implementation for assorted
+        // key lengths, positions, and data lengths.
+        let mut rng = thread_rng();
+        for &klen in &[1usize, 3, 8, 13, 256, 4099, 65535] {
+            let key: Vec<u8> = (0..klen).map(|_| rng.gen()).collect();
+            let stream = KeyStream::new(&key);
+            for &(position, length) in &[(0usize, 0usize), (1, 7), (5, 100), (12345, 40000), (7, 70001)] {
+                let original: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+                let mut data = original.clone();
+                stream.apply(position, &mut data);
+                for index in 0..length {
+                    assert_eq!(data[index], original[index] ^ key[(index + position) % klen]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn crypt_test_6() {
+        let mut data = [18u8, 21u8];
+        KeyStream::new(&[]).apply(0, &mut data);
+    }
+
+    #[test]
+    fn crypt_test_7() {
+        // The ChaCha20 key stream is position addressable.
+        let cipher = CipherKind::CHACHA20.cipher(&[0x55, 0x21, 0xe4, 0x9a]);
+        let mut whole = vec![0u8; 1000];
+        cipher.apply(0, &mut whole);
+        assert!(whole.iter().any(|byte| *byte != 0));
+        let mut part = vec![0u8; 333];
+        cipher.apply(555, &mut part);
+        assert_eq!(&part[..], &whole[555..888]);
+        let mut part = vec![0u8; 333];
+        CipherKind::CHACHA20.apply(&[0x55, 0x21, 0xe4, 0x9a], 555, &mut part);
+        assert_eq!(&part[..], &whole[555..888]);
+        let mut data = whole.clone();
+        cipher.apply(0, &mut data);
+        assert_eq!(data, vec![0u8; 1000]);
+
+        // Writes that cross a segment boundary use both segments.
+        let boundary = 1usize << super::SEGMENT_BITS;
+        let mut across = vec![0u8; 128];
+        cipher.apply(boundary - 64, &mut across);
+        let mut before = vec![0u8; 64];
+        cipher.apply(boundary - 64, &mut before);
+        let mut after = vec![0u8; 64];
+        cipher.apply(boundary, &mut after);
+        assert_eq!(&across[..64], &before[..]);
+        assert_eq!(&across[64..], &after[..]);
+        assert_eq!(CipherKind::from_id(CipherKind::CHACHA20.id()), Some(CipherKind::CHACHA20));
+        assert_eq!(CipherKind::from_id(7), None);
+    }
 }
@@ -4,7 +4,8 @@ use std::io;
 use std::error::Error;
 use std::result;
 use std::fmt;
-use constants::{MAJOR, MINOR};
+use constants::{MAJOR, MINOR, EXTENSIONS_MAJOR};
+use header::HashAlgorithm;
 
 /// Report an error in handling a Pico-encoded file.
 #[derive(Debug)]
@@ -29,13 +30,48 @@ pub enum PicoError {
     /// This library cannot handle the Pico-encoded file's version.  Include
     /// the major and minor version numbers of the file.
     BadVersion(u16, u16),
-    /// The key has zero length, which is not allowed.
+    /// The key has zero length, or more than 65535 bytes, which is not
+    /// allowed.
     KeyError,
     /// The specified offset is invalid.  Include the offset value and the
     /// minimum offset value based on the header.
     BadOffset(u32, u32),
     /// An error occurred in computing the hash.
     HashError,
+    /// The hash of the data does not match the hash stored in the header.
+    /// Include both hashes.
+    HashMismatch {
+        /// The hash algorithm that detected the difference.
+        algorithm: HashAlgorithm,
+        /// The hash stored in the header.
+        stored: Vec<u8>,
+        /// The hash computed from the data.
+        computed: Vec<u8>,
+    },
+    /// The header version cannot hold extensions.  Include the major and
+    /// minor version numbers of the header.
+    ExtensionsNotSupported(u16, u16),
+    /// The header extension area is malformed.  Include the length of the
+    /// extension area.
+    BadExtension(u32),
+    /// The cipher recorded in the header is not known.  Include the cipher
+    /// identifier.
+    UnknownCipher(u16),
+    /// The structured metadata is malformed.  Include the offset within
+    /// the metadata where the problem was found.
+    BadMetadata(u32),
+    /// The metadata does not fit in the space reserved for it.  Include
+    /// the number of bytes needed and the number reserved.
+    MetadataTooLarge(u32, u32),
+    /// The file ends before the end of its header or metadata.  Include
+    /// the length the file needs to have, at least.
+    Truncated(u64),
+    /// A provenance tag contains a line break, which would split it into
+    /// several tags when read back.  Include the tag.
+    BadTag(String),
+    /// A length in the header exceeds a limit set when opening the file.
+    /// Include the name of the field, its length, and the limit.
+    LimitExceeded(&'static str, u64, u64),
     /// A hrung collapsed somewhere.  Provide a unique id for the error.
     InternalError(u32),
 }
@@ -50,19 +86,28 @@ impl Error for PicoError {
             PicoError::WriteFailed(_, _) => r#"Writing to a file failed."#,
             PicoError::NotPico(_) => r#"The file does not appear to be a Pico-encoded file."#,
             PicoError::BadVersion(_, _) => r#"This version of the library cannot read the version of the Pico encoding used in the file."#,
-            PicoError::KeyError => r#"A key cannot have zero length."#,
+            PicoError::KeyError => r#"A key must have between 1 and 65535 bytes."#,
             PicoError::BadOffset(_, _) => r#"The data offset in the file is incorrect."#,
             PicoError::HashError => r#"An error occurred computing the hash."#,
+            PicoError::HashMismatch { .. } => r#"The data does not match the stored hash."#,
+            PicoError::ExtensionsNotSupported(_, _) => r#"This version of the Pico encoding does not support header extensions."#,
+            PicoError::BadExtension(_) => r#"The header extension area is malformed."#,
+            PicoError::UnknownCipher(_) => r#"The file uses an unknown cipher."#,
+            PicoError::BadMetadata(_) => r#"The structured metadata is malformed."#,
+            PicoError::MetadataTooLarge(_, _) => r#"The metadata does not fit in the space reserved for it."#,
+            PicoError::Truncated(_) => r#"The file is truncated."#,
+            PicoError::LimitExceeded(_, _, _) => r#"The file exceeds a limit set when opening it."#,
+            PicoError::BadTag(_) => r#"A provenance tag cannot contain a line break."#,
             PicoError::InternalError(_) => r#"An internal error was detected in the pico library."#,
         }
     }
-    fn cause(&self) -> Option<&Error> {
+    fn cause(&self) -> Option<&dyn Error> {
         Some(match *self {
-            PicoError::FileNotFound(_, _, ref err) => err as &Error,
-            PicoError::FileExists(_, _, ref err) => err as &Error,
-            PicoError::SeekFailed(_, ref err) => err as &Error,
-            PicoError::WriteFailed(_, ref err) => err as &Error,
-            PicoError::ReadFailed(_, ref err) => err as &Error,
+            PicoError::FileNotFound(_, _, ref err) => err as &dyn Error,
+            PicoError::FileExists(_, _, ref err) => err as &dyn Error,
+            PicoError::SeekFailed(_, ref err) => err as &dyn Error,
+            PicoError::WriteFailed(_, ref err) => err as &dyn Error,
+            PicoError::ReadFailed(_, ref err) => err as &dyn Error,
             _ => {
                 return None;
             }
@@ -73,6 +118,7 @@ impl Error for PicoError {
 impl fmt::Display for PicoError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // We use the description for display, and then add additional information where appropriate.
+        #[allow(deprecated)]
         let res = write!(f, "{} ", self.description());
         match *self {
             PicoError::FileNotFound(_, ref name, _) =>
@@ -98,10 +144,62 @@ impl fmt::Display for PicoError {
                     r#"The header extends to at least offset 0x{:X}, but the file specifies the data offset as 0x{:X}."#,
                     minoffset, badoffset
                 ),
+            PicoError::ExtensionsNotSupported(major, minor) =>
+                write!(
+                    f,
+                    r#"Version {}.{} has no extension area, but extensions require version {}.0 or later."#,
+                    major, minor, EXTENSIONS_MAJOR
+                ),
+            PicoError::BadExtension(length) =>
+                write!(f, r#"An entry overruns the {} byte extension area."#, length),
+            PicoError::UnknownCipher(id) =>
+                write!(f, r#"The cipher identifier is {}."#, id),
+            PicoError::BadMetadata(offset) =>
+                write!(f, r#"The problem was found at metadata offset {}."#, offset),
+            PicoError::MetadataTooLarge(needed, reserved) =>
+                write!(
+                    f,
+                    r#"The metadata needs {} bytes, but only {} bytes are reserved."#,
+                    needed, reserved
+                ),
+            PicoError::Truncated(length) =>
+                write!(f, r#"The file must be at least {} bytes long."#, length),
+            PicoError::LimitExceeded(field, length, limit) =>
+                write!(
+                    f,
+                    r#"The {} is {} bytes long, but the limit is {} bytes."#,
+                    field, length, limit
+                ),
+            PicoError::BadTag(ref tag) =>
+                write!(f, r#"The tag is {:?}."#, tag),
+            PicoError::HashMismatch { algorithm, ref stored, ref computed } => {
+                let hex = |bytes: &[u8]| -> String {
+                    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+                };
+                write!(
+                    f,
+                    r#"The stored {:?} hash is {}, but the data hashes to {}."#,
+                    algorithm, hex(stored), hex(computed)
+                )
+            },
             _ => res,
         }
     }
 }
 
+impl From<PicoError> for io::Error {
+    /// Convert to an `io::Error` so that Pico errors can be reported
+    /// through the standard I/O traits.  Errors that wrap an underlying
+    /// I/O error are unwrapped.
+    fn from(err: PicoError) -> io::Error {
+        match err {
+            PicoError::SeekFailed(_, err) |
+            PicoError::ReadFailed(_, err) |
+            PicoError::WriteFailed(_, err) => err,
+            other => io::Error::other(other),
+        }
+    }
+}
+
 /// A wrapper for easier use of the result type.
 pub type Result<T> = result::Result<T, PicoError>;
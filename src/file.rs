@@ -1,25 +1,235 @@
 //! File operations for Pico encoding and decoding.
 
-use pico::Pico;
+use pico::{Pico, read_fully};
+use builder::PicoBuilder;
 use header::HeaderFormat;
 use constants::CHUNK_SIZE;
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
+use std::fs::{File, OpenOptions, remove_file, rename};
+#[cfg(unix)]
+use std::fs::{Permissions, set_permissions};
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread::{available_parallelism, scope};
+use crypt::Cipher;
 use errors::{Result, PicoError};
+use provenance::Provenance;
+use tempfile::tempfile;
 
-pub fn encode(
-    from: &String, 
-    to: &String, 
-    key: Vec<u8>, 
-    metadata: Vec<u8>, 
+/// Get a printable name for a path, for use in error messages.
+fn name<P: AsRef<Path>>(path: &P) -> String {
+    path.as_ref().to_string_lossy().into_owned()
+}
+
+/// Attributes of the original file to restore when decoding.  The
+/// attributes come from the provenance stored in the encoded file.  By
+/// default nothing is restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Restore {
+    /// Name the decoded file with the original file name.
+    pub filename: bool,
+    /// Set the modification time to that of the original file.
+    pub modified: bool,
+    /// Set the permission bits to those of the original file, except for
+    /// the executable bits.
+    pub permissions: bool,
+    /// Also restore the executable bits.  This has no effect unless
+    /// `permissions` is also set.
+    pub executable: bool,
+}
+
+impl Restore {
+    /// Get the path of the decoded file.  If the original file name is
+    /// restored and known, the decoded file goes in the same folder as
+    /// `to`, with the original name.  Otherwise the decoded file is `to`.
+    pub fn path<Q: AsRef<Path>>(&self, provenance: Option<&Provenance>, to: Q) -> PathBuf {
+        let to = to.as_ref();
+        match provenance.and_then(|provenance| provenance.safe_filename()) {
+            Some(filename) if self.filename => to.with_file_name(filename),
+            _ => to.to_path_buf(),
+        }
+    }
+
+    /// Restore the attributes of the original file to a decoded file.
+    /// Attributes that were not recorded are left alone.  Set-user-ID,
+    /// set-group-ID, and sticky bits are never restored.
+    pub fn apply<Q: AsRef<Path>>(&self, provenance: Option<&Provenance>, to: Q) -> Result<()> {
+        let provenance = match provenance {
+            None => return Ok(()),
+            Some(provenance) => provenance,
+        };
+        if let (true, Some(time)) = (self.modified, provenance.modified) {
+            let target = OpenOptions::new()
+                .write(true)
+                .open(&to)
+                .map_err(|err| {
+                    PicoError::FileNotFound(2075, name(&to), err)
+                })?;
+            target.set_modified(time)
+                .map_err(|err| { PicoError::WriteFailed(2076, err) })?;
+        }
+        if let (true, Some(mode)) = (self.permissions, provenance.mode) {
+            let mask = if self.executable { 0o777 } else { 0o666 };
+            set_mode(&to, mode & mask)?;
+        }
+        Ok(())
+    }
+}
+
+/// Set the permission bits of a file.
+#[cfg(unix)]
+fn set_mode<Q: AsRef<Path>>(to: &Q, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    set_permissions(to, Permissions::from_mode(mode))
+        .map_err(|err| { PicoError::WriteFailed(2077, err) })
+}
+
+/// Set the permission bits of a file.  Only Unix has permission bits, so
+/// elsewhere this does nothing.
+#[cfg(not(unix))]
+fn set_mode<Q: AsRef<Path>>(_to: &Q, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Get the options for encoding with the given key, initial metadata, and
+/// reserve.  As with `Pico::put_metadata`, metadata past the reserve is
+/// dropped.
+fn file_options(key: Vec<u8>, mut metadata: Vec<u8>, reserve: u32) -> PicoBuilder {
+    metadata.truncate(reserve as usize);
+    let mut options = PicoBuilder::new();
+    options.key(key).metadata(metadata).reserve(reserve);
+    options
+}
+
+/// Encode data from any stream into a Pico-encoded target.  To choose
+/// other options, such as the version of the encoding, use
+/// `encode_stream_with`.
+///
+/// # Arguments
+/// * `source`   - The stream providing the data to encode.
+/// * `target`   - The target to get the Pico-encoded file.  This must
+///   support seeking, since the header is rewritten once the hash is known.
+/// * `key`      - The encryption key to use.
+/// * `metadata` - Initial metadata to store.
+/// * `reserve`  - The number of bytes to reserve for metadata.
+pub fn encode_stream<R: Read, T: Read + Write + Seek>(
+    source: &mut R,
+    target: T,
+    key: Vec<u8>,
+    metadata: Vec<u8>,
     reserve: u32) -> Result<()> {
+    encode_stream_with(source, target, &file_options(key, metadata, reserve))
+}
+
+/// Encode data from any stream into a Pico-encoded target, creating the
+/// target with the given options.
+///
+/// # Arguments
+/// * `source`  - The stream providing the data to encode.
+/// * `target`  - The target to get the Pico-encoded file.  This must
+///   support seeking, since the header is rewritten once the hash is known.
+/// * `options` - The key, metadata, version, and other options to use.
+pub fn encode_stream_with<R: Read, T: Read + Write + Seek>(
+    source: &mut R,
+    target: T,
+    options: &PicoBuilder) -> Result<()> {
+    // Create the Pico structure and write the metadata.
+    let mut pico = options.create(target)?;
+
+    // Now read chunks from the input and write them encoded
+    // into the output.
+    let mut position: usize = 0;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        // Read a chunk from the input.  Pipes may return fewer bytes than
+        // requested, so keep reading until the chunk is full.
+        let count = read_fully(source, &mut buffer)
+            .map_err(|err| { PicoError::ReadFailed(2003, err) })?;
+        if count == 0 { break; }
+
+        // Encode and write the chunk to the output.
+        pico.put(position, &mut buffer[0..count])?;
+        position += count;
+    }
+
+    // Done encoding.  Flush the Pico file.
+    pico.flush()?;
+    Ok(())
+}
+
+/// Decode a Pico-encoded source into any stream.
+///
+/// # Arguments
+/// * `source` - The Pico-encoded file.  Only read access is needed, and
+///   the source is never modified.
+/// * `target` - The stream to get the decoded data.
+pub fn decode_stream<T: Read + Seek, W: Write>(
+    source: T,
+    target: &mut W) -> Result<()> {
+    // Create the Pico structure.
+    let mut pico = Pico::open(source)?;
+
+    // Now read chunks from the input and write them decoded
+    // into the output.
+    let mut position: usize = 0;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        // Read a chunk from the input.
+        let count = pico.get(position, &mut buffer)?;
+        if count == 0 { break; }
+
+        // Write the decoded chunk to the output.
+        target.write_all(&buffer[0..count])
+            .map_err(|err| { PicoError::WriteFailed(2012, err) })?;
+        position += count;
+    }
+
+    // Done decoding.  Flush the output.  The Pico file is not flushed,
+    // since nothing in it has changed.
+    target.flush()
+        .map_err(|err| { PicoError::WriteFailed(2013, err) })?;
+    Ok(())
+}
+
+/// Encode a file.  The target file must not already exist.  To choose
+/// other options, such as the version of the encoding or the provenance to
+/// record, use `encode_with`.
+///
+/// # Arguments
+/// * `from`     - The file to encode.
+/// * `to`       - The Pico-encoded file to create.
+/// * `key`      - The encryption key to use.
+/// * `metadata` - Initial metadata to store.
+/// * `reserve`  - The number of bytes to reserve for metadata.
+pub fn encode<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    to: Q,
+    key: Vec<u8>,
+    metadata: Vec<u8>,
+    reserve: u32) -> Result<()> {
+    encode_with(from, to, &file_options(key, metadata, reserve))
+}
+
+/// Encode a file, creating the Pico-encoded file with the given options.
+/// The target file must not already exist.  Any provenance set in the
+/// options, such as from `Provenance::for_file`, is merged into the
+/// initial metadata.
+///
+/// # Arguments
+/// * `from`    - The file to encode.
+/// * `to`      - The Pico-encoded file to create.
+/// * `options` - The key, metadata, version, and other options to use.
+pub fn encode_with<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    to: Q,
+    options: &PicoBuilder) -> Result<()> {
     // Open the file to read.
     let mut source = OpenOptions::new()
         .create(false)
         .read(true)
-        .open(from)
+        .open(&from)
         .map_err(|err| {
-            PicoError::FileNotFound(2001, from.clone(), err)
+            PicoError::FileNotFound(2001, name(&from), err)
         })?;
 
     // Open the file to write.
@@ -27,103 +237,806 @@ pub fn encode(
         .create_new(true)
         .read(true)
         .write(true)
-        .open(to)
+        .open(&to)
         .map_err(|err| {
-            PicoError::FileExists(2002, to.clone(), err)
+            PicoError::FileExists(2002, name(&to), err)
         })?;
 
-    // Create the Pico structure.
-    let mut pico = Pico::new(target, key, reserve)?;
+    // Encode.  The files get dropped when done, which closes them.
+    encode_stream_with(&mut source, target, options)
+}
 
-    // Write the metadata.
-    pico.put_metadata(0, &metadata)?;
+/// Decode a Pico-encoded file.  The target file must not already exist.
+/// The source file is opened read-only and is never modified.  To restore
+/// the name and attributes of the original file, use `decode_restore`.
+///
+/// # Arguments
+/// * `from` - The Pico-encoded file to decode.
+/// * `to`   - The file to create with the decoded data.
+pub fn decode<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    to: Q) -> Result<()> {
+    // Open the file to read.  The source is never modified, so only read
+    // access is requested.
+    let source = OpenOptions::new()
+        .create(false)
+        .read(true)
+        .open(&from)
+        .map_err(|err| {
+            PicoError::FileNotFound(2010, name(&from), err)
+        })?;
 
-    // Now read chunks from the input file and write them encoded
-    // into the output file.
-    let mut position: usize = 0;
-    let mut buffer = vec![0u8; CHUNK_SIZE];
-    loop {
-        // Read a chunk from the input file.
-        let count = source.read(&mut buffer)
-            .map_err(|err| { PicoError::ReadFailed(2003, err) })?;
-        if count == 0 { break; }
+    // Open the file to write.
+    let mut target = OpenOptions::new()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(&to)
+        .map_err(|err| {
+            PicoError::FileExists(2011, name(&to), err)
+        })?;
 
-        // Encode and write the chunk to the output file.
-        pico.put(position, &mut buffer[0..count])?;
-        position += count;
-    }
+    // Decode.  The files get dropped when done, which closes them.
+    decode_stream(source, &mut target)
+}
 
-    // Done encoding.  Flush the Pico file and then let the
-    // files get dropped, which closes them.
-    pico.flush()?;
-    Ok(())
+/// Decode a Pico-encoded file, restoring the name and attributes of the
+/// original file as requested.  The decoded file must not already exist.
+/// The source file is opened read-only and is never modified.
+///
+/// # Arguments
+/// * `from`    - The Pico-encoded file to decode.
+/// * `to`      - The file to create with the decoded data.  If the original
+///   file name is restored, only its folder is used.
+/// * `restore` - The attributes to restore.
+/// * `threads` - The number of threads to use as for `decode_parallel`, or
+///   `None` to decode on this thread.
+///
+/// The path of the decoded file is returned.
+pub fn decode_restore<P: AsRef<Path> + Sync, Q: AsRef<Path>>(
+    from: P,
+    to: Q,
+    restore: &Restore,
+    threads: Option<usize>) -> Result<PathBuf> {
+    let provenance = read_provenance(&from)?;
+    let target = restore.path(provenance.as_ref(), to);
+    match threads {
+        None => decode(&from, &target)?,
+        Some(count) => decode_parallel(&from, &target, count)?,
+    }
+    restore.apply(provenance.as_ref(), &target)?;
+    Ok(target)
 }
 
-pub fn decode(
-    from: &String, 
-    to: &String) -> Result<()> {
+/// Read the provenance stored in the metadata of a Pico-encoded file.  If
+/// no provenance is stored, or the metadata cannot be decoded, `None` is
+/// returned.  The file is opened read-only and is never modified.
+pub fn read_provenance<P: AsRef<Path>>(from: P) -> Result<Option<Provenance>> {
     // Open the file to read.
     let source = OpenOptions::new()
         .create(false)
         .read(true)
-        .write(true)
+        .open(&from)
+        .map_err(|err| {
+            PicoError::FileNotFound(2078, name(&from), err)
+        })?;
+    let mut pico = Pico::open(source)?;
+    Ok(pico.read_metadata_map().ok()
+        .and_then(|metadata| Provenance::from_metadata(&metadata)))
+}
+
+/// Split data into contiguous ranges, one for each thread.  Ranges are
+/// multiples of the chunk size, except possibly the last.
+fn split(length: u64, threads: usize) -> Vec<(u64, u64)> {
+    let chunk = CHUNK_SIZE as u64;
+    let chunks = length.div_ceil(chunk);
+    let per_thread = chunks.div_ceil(threads.max(1) as u64).max(1) * chunk;
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < length {
+        let end = (start + per_thread).min(length);
+        ranges.push((start, end));
+        start = end;
+    }
+    ranges
+}
+
+/// Get the number of threads to use.  Zero means one per available core.
+fn thread_count(threads: usize) -> usize {
+    if threads > 0 {
+        threads
+    } else {
+        available_parallelism().map(|count| count.get()).unwrap_or(1)
+    }
+}
+
+/// Encrypt or decrypt a range of the data, reading from one file and
+/// writing to another.  Each call opens its own handles, so calls can run
+/// concurrently on different ranges of the same files.
+///
+/// # Arguments
+/// * `from`      - The file to read.
+/// * `from_base` - Offset of the first data byte in the file to read.
+/// * `to`        - The file to write.
+/// * `to_base`   - Offset of the first data byte in the file to write.
+/// * `range`     - Zero-based start and end positions within the data.
+/// * `cipher`    - The cipher to apply.
+fn transcode_range<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: &P,
+    from_base: u64,
+    to: &Q,
+    to_base: u64,
+    range: (u64, u64),
+    cipher: &dyn Cipher) -> Result<()> {
+    let (start, end) = range;
+    let mut source = OpenOptions::new()
+        .read(true)
         .open(from)
         .map_err(|err| {
-            PicoError::FileNotFound(2010, from.clone(), err)
+            PicoError::FileNotFound(2050, name(from), err)
+        })?;
+    let mut target = OpenOptions::new()
+        .write(true)
+        .open(to)
+        .map_err(|err| {
+            PicoError::FileNotFound(2051, name(to), err)
+        })?;
+    source.seek(SeekFrom::Start(from_base + start))
+        .map_err(|err| { PicoError::SeekFailed(2052, err) })?;
+    target.seek(SeekFrom::Start(to_base + start))
+        .map_err(|err| { PicoError::SeekFailed(2053, err) })?;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut position = start;
+    while position < end {
+        let count = ((end - position) as usize).min(CHUNK_SIZE);
+        source.read_exact(&mut buffer[0..count])
+            .map_err(|err| { PicoError::ReadFailed(2054, err) })?;
+        cipher.apply(position as usize, &mut buffer[0..count]);
+        target.write_all(&buffer[0..count])
+            .map_err(|err| { PicoError::WriteFailed(2055, err) })?;
+        position += count as u64;
+    }
+    Ok(())
+}
+
+/// Encrypt or decrypt ranges of the data on several threads at once.
+fn transcode_parallel<P: AsRef<Path> + Sync, Q: AsRef<Path> + Sync>(
+    from: &P,
+    from_base: u64,
+    to: &Q,
+    to_base: u64,
+    ranges: Vec<(u64, u64)>,
+    cipher: &dyn Cipher) -> Result<()> {
+    scope(|scope| {
+        let workers: Vec<_> = ranges.into_iter().map(|range| {
+            scope.spawn(move || {
+                transcode_range(from, from_base, to, to_base, range, cipher)
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap_or(Err(PicoError::InternalError(2056)))?;
+        }
+        Ok(())
+    })
+}
+
+/// Encode a file using several threads.  The target file must not already
+/// exist.  To choose other options, such as the version of the encoding or
+/// the provenance to record, use `encode_parallel_with`.
+///
+/// # Arguments
+/// * `from`     - The file to encode.
+/// * `to`       - The Pico-encoded file to create.
+/// * `key`      - The encryption key to use.
+/// * `metadata` - Initial metadata to store.
+/// * `reserve`  - The number of bytes to reserve for metadata.
+/// * `threads`  - The number of threads to use, or zero to use one for
+///   each available core.
+pub fn encode_parallel<P: AsRef<Path> + Sync, Q: AsRef<Path> + Sync>(
+    from: P,
+    to: Q,
+    key: Vec<u8>,
+    metadata: Vec<u8>,
+    reserve: u32,
+    threads: usize) -> Result<()> {
+    encode_parallel_with(from, to, &file_options(key, metadata, reserve), threads)
+}
+
+/// Encode a file using several threads, creating the Pico-encoded file
+/// with the given options.  The target file must not already exist.  As
+/// with `encode_with`, any provenance set in the options is merged into
+/// the initial metadata.
+///
+/// Because the encryption of each byte depends only on its position, the
+/// data is split into ranges that are encrypted concurrently, each thread
+/// using its own file handles.  The hash is computed by the calling thread
+/// at the same time, reading the source a second time.  Computing the
+/// hash is inherently sequential, so it bounds the speed of encoding.
+///
+/// If the source is not a regular file, such as a pipe or a device, or
+/// reports a length of zero, its length is not known in advance, and it is
+/// encoded on the calling thread as by `encode_with`.
+///
+/// # Arguments
+/// * `from`    - The file to encode.
+/// * `to`      - The Pico-encoded file to create.
+/// * `options` - The key, metadata, version, and other options to use.
+/// * `threads` - The number of threads to use, or zero to use one for
+///   each available core.
+pub fn encode_parallel_with<P: AsRef<Path> + Sync, Q: AsRef<Path> + Sync>(
+    from: P,
+    to: Q,
+    options: &PicoBuilder,
+    threads: usize) -> Result<()> {
+    // Open the file to read.
+    let mut source = OpenOptions::new()
+        .create(false)
+        .read(true)
+        .open(&from)
+        .map_err(|err| {
+            PicoError::FileNotFound(2057, name(&from), err)
         })?;
+    let attributes = source.metadata()
+        .map_err(|err| { PicoError::ReadFailed(2058, err) })?;
 
     // Open the file to write.
-    let mut target = OpenOptions::new()
+    let target = OpenOptions::new()
         .create_new(true)
         .read(true)
         .write(true)
-        .open(to)
+        .open(&to)
         .map_err(|err| {
-            PicoError::FileExists(2011, to.clone(), err)
+            PicoError::FileExists(2059, name(&to), err)
         })?;
 
-    // Create the Pico structure.
+    // Only a regular file has a known length and can be read at any
+    // position.  Anything else, such as a pipe or a device, is encoded on
+    // this thread.  Some files, such as those in /proc, claim to be empty
+    // but are not, and an empty file gains nothing from threads anyway.
+    let length = attributes.len();
+    if !attributes.is_file() || length == 0 {
+        return encode_stream_with(&mut source, target, options);
+    }
+
+    // Create the Pico structure and write the metadata.
+    let mut pico = options.create(target)?;
+    let offset = pico.get_offset() as u64;
+    let cipher = pico.get_header().get_cipher()?.cipher(&pico.get_header().key);
+
+    // Encrypt the data on worker threads while hashing it on this one.
+    let ranges = split(length, thread_count(threads));
+    let hashes = scope(|scope| {
+        let workers = scope.spawn(|| {
+            transcode_parallel(&from, 0, &to, offset, ranges, &*cipher)
+        });
+        let mut context = pico.new_hashes();
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut position = 0;
+        while position < length {
+            let count = ((length - position) as usize).min(CHUNK_SIZE);
+            source.read_exact(&mut buffer[0..count])
+                .map_err(|err| { PicoError::ReadFailed(2060, err) })?;
+            context.consume(&buffer[0..count]);
+            position += count as u64;
+        }
+        workers.join().unwrap_or(Err(PicoError::InternalError(2061)))?;
+        Ok(context)
+    })?;
+
+    // Done encoding.  Store the hashes and flush the Pico file.
+    pico.set_hash(&hashes)?;
+    pico.flush()?;
+    Ok(())
+}
+
+/// Decode a Pico-encoded file using several threads.  The target file must
+/// not already exist.  The source file is opened read-only and is never
+/// modified.
+///
+/// # Arguments
+/// * `from`    - The Pico-encoded file to decode.
+/// * `to`      - The file to create with the decoded data.
+/// * `threads` - The number of threads to use, or zero to use one for
+///   each available core.
+pub fn decode_parallel<P: AsRef<Path> + Sync, Q: AsRef<Path> + Sync>(
+    from: P,
+    to: Q,
+    threads: usize) -> Result<()> {
+    // Open the file to read and get the header.
+    let source = OpenOptions::new()
+        .create(false)
+        .read(true)
+        .open(&from)
+        .map_err(|err| {
+            PicoError::FileNotFound(2062, name(&from), err)
+        })?;
     let mut pico = Pico::open(source)?;
+    let offset = pico.get_offset() as u64;
+    let length = pico.data_len()?;
+    let cipher = pico.get_header().get_cipher()?.cipher(&pico.get_header().key);
 
-    // Now read chunks from the input file and write them decoded
-    // into the output file.
-    let mut position: usize = 0;
+    // Create the file to write at its full length.
+    let target = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(&to)
+        .map_err(|err| {
+            PicoError::FileExists(2063, name(&to), err)
+        })?;
+    target.set_len(length)
+        .map_err(|err| { PicoError::WriteFailed(2064, err) })?;
+
+    // Decrypt the data on worker threads.
+    let ranges = split(length, thread_count(threads));
+    transcode_parallel(&from, offset, &to, 0, ranges, &*cipher)
+}
+
+/// Verify the integrity of a Pico-encoded file by comparing the hash of
+/// the data with the hash stored in the header.  The file is opened
+/// read-only and is never modified.
+pub fn verify<P: AsRef<Path>>(from: P) -> Result<()> {
+    // Open the file to read.
+    let source = OpenOptions::new()
+        .create(false)
+        .read(true)
+        .open(&from)
+        .map_err(|err| {
+            PicoError::FileNotFound(2040, name(&from), err)
+        })?;
+
+    // Check the hash.
+    let mut pico = Pico::open(source)?;
+    pico.verify()
+}
+
+/// Get the name of the temporary file used while rewriting a file.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    PathBuf::from(temp)
+}
+
+/// Modify a copy of a file, and then replace the original with the copy,
+/// so the original is left intact if anything fails.  The copy is made
+/// next to the original, with `.tmp` appended to its name, and gets the
+/// permissions of the original.
+fn rewrite<F>(path: &Path, modify: F) -> Result<()>
+where
+    F: FnOnce(&mut File) -> Result<()>,
+{
+    let temp = temp_path(path);
+    let mut source = File::open(path)
+        .map_err(|err| { PicoError::FileNotFound(2070, name(&path), err) })?;
+    let mut target = OpenOptions::new()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(&temp)
+        .map_err(|err| { PicoError::FileExists(2071, name(&temp), err) })?;
+
+    // Modify the copy.  If that fails, discard it.
+    let result = io::copy(&mut source, &mut target)
+        .map_err(|err| { PicoError::WriteFailed(2072, err) })
+        .and_then(|_| modify(&mut target))
+        .and_then(|_| target.sync_all()
+            .map_err(|err| { PicoError::WriteFailed(2073, err) }))
+        .and_then(|_| source.metadata()
+            .and_then(|metadata| target.set_permissions(metadata.permissions()))
+            .map_err(|err| { PicoError::WriteFailed(2079, err) }));
+    if let Err(err) = result {
+        let _ = remove_file(&temp);
+        return Err(err);
+    }
+
+    // Replace the original.
+    rename(&temp, path)
+        .map_err(|err| { PicoError::WriteFailed(2074, err) })
+}
+
+/// Change the number of bytes reserved for metadata in a Pico-encoded
+/// file.  Existing metadata is kept, up to the new length, and any new
+/// metadata bytes are zero.
+///
+/// The resize is done on a copy of the file, which then replaces the
+/// original, so the original is left intact if anything fails.  The copy
+/// is made next to the original, with `.tmp` appended to its name.
+///
+/// # Arguments
+/// * `path`      - The Pico-encoded file to modify.
+/// * `md_length` - The number of bytes to reserve for metadata.
+pub fn resize_metadata<P: AsRef<Path>>(path: P, md_length: u32) -> Result<()> {
+    rewrite(path.as_ref(), |target| Pico::open(target)?.resize_metadata(md_length))
+}
+
+/// Change the key of a Pico-encoded file.  The data is re-encrypted
+/// without ever writing the plaintext to disk, and the metadata and hashes
+/// are kept.
+///
+/// As with `resize_metadata`, the change is made to an encrypted copy of
+/// the file, which then replaces the original.
+///
+/// # Arguments
+/// * `path` - The Pico-encoded file to modify.
+/// * `key`  - The new encryption key.  This cannot be empty.
+pub fn rekey<P: AsRef<Path>>(path: P, key: Vec<u8>) -> Result<()> {
+    rewrite(path.as_ref(), |target| Pico::open(target)?.rekey(key))
+}
+
+/// Copy everything from one stream to another.  Failures to read and to
+/// write are reported separately, with the given error ids.
+fn copy_stream<R: Read, W: Write>(
+    source: &mut R,
+    target: &mut W,
+    read_id: u32,
+    write_id: u32) -> Result<()> {
     let mut buffer = vec![0u8; CHUNK_SIZE];
     loop {
-        // Read a chunk from the input file.
-        let count = pico.get(position, &mut buffer)?;
+        let count = read_fully(source, &mut buffer)
+            .map_err(|err| { PicoError::ReadFailed(read_id, err) })?;
         if count == 0 { break; }
-
-        // Encode and write the chunk to the output file.
-        target.write(&buffer[0..count])
-            .map_err(|err| { PicoError::WriteFailed(2012, err) })?;
-        position += count;
+        target.write_all(&buffer[0..count])
+            .map_err(|err| { PicoError::WriteFailed(write_id, err) })?;
     }
+    Ok(())
+}
 
-    // Done encoding.  Flush the Pico file and then let the
-    // files get dropped, which closes them.
-    pico.flush()?;
+/// Encode data from a stream to a target that need not support seeking,
+/// such as a pipe or standard output.
+///
+/// The Pico header includes the hash of the data, and the header must be
+/// written before the data.  To avoid seeking the target, the entire
+/// encoded file is assembled in an anonymous temporary file and then
+/// copied to the target, so this requires temporary disk space, but not
+/// memory, proportional to the size of the input.  The data is encrypted
+/// before it is written to the temporary file.
+///
+/// # Arguments
+/// * `source`   - The stream providing the data to encode.
+/// * `target`   - The stream to get the Pico-encoded file.
+/// * `key`      - The encryption key to use.
+/// * `metadata` - Initial metadata to store.
+/// * `reserve`  - The number of bytes to reserve for metadata.
+pub fn encode_pipe<R: Read, W: Write>(
+    source: &mut R,
+    target: &mut W,
+    key: Vec<u8>,
+    metadata: Vec<u8>,
+    reserve: u32) -> Result<()> {
+    encode_pipe_with(source, target, &file_options(key, metadata, reserve))
+}
+
+/// Encode data into a stream that need not support seeking, creating the
+/// Pico-encoded file with the given options.  As with `encode_pipe`, the
+/// encoded file is first written to an anonymous temporary file.
+///
+/// # Arguments
+/// * `source`  - The stream providing the data to encode.
+/// * `target`  - The stream to get the Pico-encoded file.
+/// * `options` - The key, metadata, version, and other options to use.
+pub fn encode_pipe_with<R: Read, W: Write>(
+    source: &mut R,
+    target: &mut W,
+    options: &PicoBuilder) -> Result<()> {
+    // Encode into a temporary file.
+    let mut spool = tempfile()
+        .map_err(|err| { PicoError::WriteFailed(2080, err) })?;
+    encode_stream_with(source, &mut spool, options)?;
+
+    // Send the complete file to the target.
+    spool.seek(SeekFrom::Start(0))
+        .map_err(|err| { PicoError::SeekFailed(2081, err) })?;
+    copy_stream(&mut spool, target, 2083, 2031)?;
+    target.flush()
+        .map_err(|err| { PicoError::WriteFailed(2032, err) })?;
     Ok(())
 }
 
-pub fn dump_header<W: Write>(
-    from: &String, 
+/// Decode a Pico-encoded file from a stream that need not support seeking,
+/// such as a pipe or standard input.  The encoded file is first copied to
+/// an anonymous temporary file, since the header must be read before the
+/// data, so this requires temporary disk space proportional to the size
+/// of the encoded file.
+///
+/// # Arguments
+/// * `source` - The stream providing the Pico-encoded file.
+/// * `target` - The stream to get the decoded data.
+pub fn decode_pipe<R: Read, W: Write>(
+    source: &mut R,
+    target: &mut W) -> Result<()> {
+    // Copy the entire encoded file.
+    let mut spool = tempfile()
+        .map_err(|err| { PicoError::WriteFailed(2082, err) })?;
+    copy_stream(source, &mut spool, 2033, 2084)?;
+
+    // Decode the data to the target.
+    decode_stream(spool, target)
+}
+
+/// Write the header of a Pico-encoded file in the given format, along with
+/// the provenance stored in its metadata, if any.  The file is opened
+/// read-only and is never modified.
+pub fn dump_header<P: AsRef<Path>, W: Write>(
+    from: P,
     mut to: W,
     format: &HeaderFormat) -> Result<()> {
     // Open the file to read.
     let source = OpenOptions::new()
         .create(false)
         .read(true)
-        .write(true)
-        .open(from)
+        .open(&from)
         .map_err(|err| {
-            PicoError::FileNotFound(2020, from.clone(), err)
+            PicoError::FileNotFound(2020, name(&from), err)
         })?;
 
     // Create the Pico structure.
-    let pico = Pico::open(source)?;
+    let mut pico = Pico::open(source)?;
 
-    // Write the header.
-    pico.dump_header(&mut to, format);
+    // Write the header.  Metadata that cannot be decoded has no
+    // provenance, but the header is still written.
+    let provenance = pico.read_metadata_map().ok()
+        .and_then(|metadata| Provenance::from_metadata(&metadata));
+    pico.get_header().dump_with_provenance(&mut to, format, provenance.as_ref());
     Ok(())
-}
\ No newline at end of file
+}
+
+#[allow(unused_imports)]
+mod test {
+    use std::io::Cursor;
+    use md5;
+    use sha2::{Digest, Sha256};
+    use pico::Pico;
+    use std::fs::{create_dir_all, remove_dir, remove_file, read, write};
+    use super::{encode_pipe, decode_pipe, encode_stream, encode_stream_with, decode_stream};
+    use super::{encode_parallel, encode_parallel_with, decode_parallel, verify, split, resize_metadata};
+    use super::{encode, encode_with, dump_header, decode_restore, read_provenance, Restore, rekey};
+    use std::fs::{metadata, set_permissions, File};
+    use std::time::{Duration, UNIX_EPOCH};
+    use header::{HashAlgorithm, HeaderFormat};
+    use provenance::Provenance;
+    use builder::PicoBuilder;
+    use errors::PicoError;
+    use std::path::Path;
+
+    #[test]
+    fn pipe_test() {
+        let text = b"Martindale\n".repeat(1000);
+        let mut encoded: Vec<u8> = Vec::new();
+        encode_pipe(&mut &text[..], &mut encoded, vec![0x55, 0x21, 0xe4, 0x9a],
+            b"Newcomb".to_vec(), 10).unwrap();
+        {
+            let pico = Pico::open(Cursor::new(encoded.clone())).unwrap();
+            assert_eq!(pico.get_hash(), md5::compute(&text).to_vec());
+            assert_eq!(pico.get_md_length(), 10);
+        }
+        let mut decoded: Vec<u8> = Vec::new();
+        decode_pipe(&mut &encoded[..], &mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        // A target that fills up is reported as a failure to write.
+        let mut small = [0u8; 100];
+        match encode_pipe(&mut &text[..], &mut &mut small[..], vec![0x55], vec![], 0) {
+            Err(PicoError::WriteFailed(2031, _)) => (),
+            _ => panic!("Expected a write failure."),
+        }
+    }
+
+    #[test]
+    fn stream_test() {
+        let text = b"Martindale\n".repeat(1000);
+        let mut encoded = Cursor::new(Vec::new());
+        encode_stream(&mut &text[..], &mut encoded, vec![0x55, 0x21, 0xe4, 0x9a],
+            vec![], 0).unwrap();
+        let mut decoded: Vec<u8> = Vec::new();
+        decode_stream(&mut encoded, &mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        // Older versions of the encoding can still be written.
+        let mut encoded = Cursor::new(Vec::new());
+        encode_stream_with(&mut &text[..], &mut encoded,
+            PicoBuilder::new().key(vec![0x55, 0x21, 0xe4, 0x9a]).version(1, 0)).unwrap();
+        {
+            let pico = Pico::open(&mut encoded).unwrap();
+            assert_eq!(pico.get_version(), (1, 0));
+            assert!(pico.get_header().extensions.is_empty());
+            assert_eq!(pico.get_hash(), md5::compute(&text).to_vec());
+        }
+        let mut decoded: Vec<u8> = Vec::new();
+        decode_stream(&mut encoded, &mut decoded).unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn split_test() {
+        assert_eq!(split(0, 4), vec![]);
+        assert_eq!(split(10, 4), vec![(0, 10)]);
+        assert_eq!(split(4096 * 5 + 1, 2), vec![(0, 4096 * 3), (4096 * 3, 4096 * 5 + 1)]);
+    }
+
+    #[test]
+    fn parallel_test() {
+        create_dir_all("_test").unwrap();
+        let text: Vec<u8> = (0..1_000_003usize).map(|index| (index * 31 % 251) as u8).collect();
+        write("_test/parallel_test.raw", &text).unwrap();
+        encode_parallel_with("_test/parallel_test.raw", "_test/parallel_test.pico",
+            PicoBuilder::new().key(vec![0x55, 0x21, 0xe4]).metadata(b"Newcomb".to_vec())
+                .reserve(10).hashes(&[HashAlgorithm::SHA256]), 4).unwrap();
+        verify("_test/parallel_test.pico").unwrap();
+        {
+            let mut pico = Pico::open(Cursor::new(read("_test/parallel_test.pico").unwrap())).unwrap();
+            assert_eq!(pico.get_hash(), md5::compute(&text).to_vec());
+            assert_eq!(pico.get_sha256().unwrap(), Sha256::digest(&text).to_vec());
+            let mut data = [0u8; 7];
+            pico.get_metadata(0, &mut data).unwrap();
+            assert_eq!(&data, b"Newcomb");
+        }
+        decode_parallel("_test/parallel_test.pico", "_test/parallel_test.out", 3).unwrap();
+        assert_eq!(read("_test/parallel_test.out").unwrap(), text);
+        remove_file("_test/parallel_test.raw").unwrap();
+        remove_file("_test/parallel_test.pico").unwrap();
+        remove_file("_test/parallel_test.out").unwrap();
+
+        // Files that report no length are still encoded in full.
+        #[cfg(target_os = "linux")]
+        {
+            let text = read("/proc/self/cmdline").unwrap();
+            assert!(!text.is_empty());
+            encode_parallel("/proc/self/cmdline", "_test/parallel_test.pico",
+                vec![0x55, 0x21, 0xe4], vec![], 0, 4).unwrap();
+            decode_parallel("_test/parallel_test.pico", "_test/parallel_test.out", 3).unwrap();
+            assert_eq!(read("_test/parallel_test.out").unwrap(), text);
+            remove_file("_test/parallel_test.pico").unwrap();
+            remove_file("_test/parallel_test.out").unwrap();
+        }
+    }
+
+    #[test]
+    fn resize_test() {
+        create_dir_all("_test").unwrap();
+        let text = b"Martindale".repeat(1000);
+        let encoded = ::encode_bytes(&text, vec![0x55, 0x21, 0xe4], b"Newcomb").unwrap();
+        write("_test/resize_test.pico", &encoded).unwrap();
+        resize_metadata("_test/resize_test.pico", 100).unwrap();
+        let (data, metadata, header) = ::decode_bytes(&read("_test/resize_test.pico").unwrap()).unwrap();
+        assert_eq!(data, text);
+        assert_eq!(header.md_length, 100);
+        assert_eq!(&metadata[0..7], b"Newcomb");
+        assert!(!Path::new("_test/resize_test.pico.tmp").exists());
+
+        // The permissions of the file are kept.
+        let original = ::std::fs::metadata("_test/resize_test.pico").unwrap().permissions();
+        let mut permissions = original.clone();
+        permissions.set_readonly(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            permissions.set_mode(0o440);
+        }
+        set_permissions("_test/resize_test.pico", permissions).unwrap();
+        resize_metadata("_test/resize_test.pico", 50).unwrap();
+        let permissions = ::std::fs::metadata("_test/resize_test.pico").unwrap().permissions();
+        assert!(permissions.readonly());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(permissions.mode() & 0o777, 0o440);
+        }
+        set_permissions("_test/resize_test.pico", original).unwrap();
+
+        // A damaged file is left alone.
+        write("_test/resize_test.pico", &encoded[0..10]).unwrap();
+        assert!(resize_metadata("_test/resize_test.pico", 100).is_err());
+        assert_eq!(read("_test/resize_test.pico").unwrap(), &encoded[0..10]);
+        assert!(!Path::new("_test/resize_test.pico.tmp").exists());
+        remove_file("_test/resize_test.pico").unwrap();
+    }
+
+    #[test]
+    fn provenance_test() {
+        create_dir_all("_test").unwrap();
+        write("_test/provenance_test.raw", b"Martindale").unwrap();
+
+        // Provenance is only recorded when asked for.
+        encode("_test/provenance_test.raw", "_test/provenance_test.pico",
+            vec![0x55, 0x21, 0xe4], vec![], 0).unwrap();
+        assert!(read_provenance("_test/provenance_test.pico").unwrap().is_none());
+        remove_file("_test/provenance_test.pico").unwrap();
+
+        // Provenance is merged with any metadata given.
+        let provenance = Provenance::for_file("_test/provenance_test.raw");
+        encode_with("_test/provenance_test.raw", "_test/provenance_test.pico",
+            PicoBuilder::new().key(vec![0x55, 0x21, 0xe4]).metadata(b"Newcomb".to_vec())
+                .provenance(&provenance)).unwrap();
+        {
+            let mut pico = Pico::open(Cursor::new(read("_test/provenance_test.pico").unwrap())).unwrap();
+            let metadata = pico.read_metadata_map().unwrap();
+            assert_eq!(metadata.get(::LEGACY_KEY), Some(&::MetadataValue::Bytes(b"Newcomb".to_vec())));
+            let provenance = Provenance::from_metadata(&metadata).unwrap();
+            assert_eq!(provenance.filename, Some("provenance_test.raw".to_string()));
+            assert!(provenance.path.unwrap().ends_with("provenance_test.raw"));
+            assert!(provenance.collected.is_some());
+        }
+        let mut output: Vec<u8> = Vec::new();
+        dump_header("_test/provenance_test.pico", &mut output, &HeaderFormat::YAML).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("provenance:\n  filename: \"provenance_test.raw\"\n"));
+        remove_file("_test/provenance_test.raw").unwrap();
+        remove_file("_test/provenance_test.pico").unwrap();
+    }
+
+    #[test]
+    fn restore_test() {
+        create_dir_all("_test/restore_test").unwrap();
+        write("_test/restore_test.bin", b"Martindale").unwrap();
+        let modified = UNIX_EPOCH + Duration::new(1_500_000_000, 0);
+        File::options().write(true).open("_test/restore_test.bin").unwrap()
+            .set_modified(modified).unwrap();
+        #[cfg(unix)]
+        {
+            use std::fs::{set_permissions, Permissions};
+            use std::os::unix::fs::PermissionsExt;
+            set_permissions("_test/restore_test.bin", Permissions::from_mode(0o755)).unwrap();
+        }
+        let provenance = Provenance::for_file("_test/restore_test.bin");
+        encode_with("_test/restore_test.bin", "_test/restore_test.pico",
+            PicoBuilder::new().key(vec![0x55, 0x21, 0xe4]).provenance(&provenance)).unwrap();
+
+        // Nothing is restored by default.
+        let restore = Restore::default();
+        let path = decode_restore("_test/restore_test.pico", "_test/restore_test/plain.raw",
+            &restore, None).unwrap();
+        assert_eq!(path, Path::new("_test/restore_test/plain.raw"));
+        assert_ne!(metadata(&path).unwrap().modified().unwrap(), modified);
+        remove_file(&path).unwrap();
+
+        // Restore the name, time, and permissions, but not the executable
+        // bits.
+        let restore = Restore { filename: true, modified: true, permissions: true, executable: false };
+        let path = decode_restore("_test/restore_test.pico", "_test/restore_test/plain.raw",
+            &restore, Some(2)).unwrap();
+        assert_eq!(path, Path::new("_test/restore_test/restore_test.bin"));
+        assert_eq!(read(&path).unwrap(), b"Martindale");
+        assert_eq!(metadata(&path).unwrap().modified().unwrap(), modified);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o644);
+        }
+        remove_file(&path).unwrap();
+
+        // Restore the executable bits when asked.
+        let restore = Restore { filename: true, modified: false, permissions: true, executable: true };
+        let path = decode_restore("_test/restore_test.pico", "_test/restore_test/plain.raw",
+            &restore, None).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o755);
+        }
+        remove_file(&path).unwrap();
+        remove_file("_test/restore_test.bin").unwrap();
+        remove_file("_test/restore_test.pico").unwrap();
+        remove_dir("_test/restore_test").unwrap();
+    }
+
+    #[test]
+    fn rekey_test() {
+        create_dir_all("_test").unwrap();
+        let text = b"Martindale".repeat(1000);
+        let encoded = ::encode_bytes(&text, vec![0x55, 0x21, 0xe4], b"Newcomb").unwrap();
+        write("_test/rekey_test.pico", &encoded).unwrap();
+        rekey("_test/rekey_test.pico", vec![0x9a, 0x21]).unwrap();
+        verify("_test/rekey_test.pico").unwrap();
+        let (data, metadata, header) = ::decode_bytes(&read("_test/rekey_test.pico").unwrap()).unwrap();
+        assert_eq!(data, text);
+        assert_eq!(metadata, b"Newcomb");
+        assert_eq!(header.key, vec![0x9a, 0x21]);
+        assert!(!Path::new("_test/rekey_test.pico.tmp").exists());
+
+        // An empty key is refused, and the file is left alone.
+        let before = read("_test/rekey_test.pico").unwrap();
+        assert!(rekey("_test/rekey_test.pico", vec![]).is_err());
+        assert_eq!(read("_test/rekey_test.pico").unwrap(), before);
+        assert!(!Path::new("_test/rekey_test.pico.tmp").exists());
+        remove_file("_test/rekey_test.pico").unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+//! Running hashes of the plaintext data.
+
+use md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use header::{HashAlgorithm, PicoHeader};
+use errors::{PicoError, Result};
+
+/// The hashes of the data, computed incrementally.  MD5 is always
+/// computed.  SHA-1 and SHA-256 are computed only if the header has room
+/// to store them.
+#[derive(Clone)]
+pub struct Hashes {
+    /// The MD5 context.
+    md5: md5::Context,
+    /// The SHA-1 context, if SHA-1 is stored.
+    sha1: Option<Sha1>,
+    /// The SHA-256 context, if SHA-256 is stored.
+    sha256: Option<Sha256>,
+}
+
+impl Hashes {
+    /// Start hashing with every algorithm the header stores.
+    pub fn new(header: &PicoHeader) -> Hashes {
+        Hashes {
+            md5: md5::Context::new(),
+            sha1: header.get_stored_hash(HashAlgorithm::SHA1).map(|_| Sha1::new()),
+            sha256: header.get_stored_hash(HashAlgorithm::SHA256).map(|_| Sha256::new()),
+        }
+    }
+
+    /// Add more data to the hashes.
+    pub fn consume(&mut self, data: &[u8]) {
+        self.md5.consume(data);
+        if let Some(ref mut context) = self.sha1 {
+            context.update(data);
+        }
+        if let Some(ref mut context) = self.sha256 {
+            context.update(data);
+        }
+    }
+
+    /// Finish the hashes, returning each algorithm with its hash.
+    pub fn compute(&self) -> Vec<(HashAlgorithm, Vec<u8>)> {
+        let mut hashes = vec![(HashAlgorithm::MD5, self.md5.compute().to_vec())];
+        if let Some(ref context) = self.sha1 {
+            hashes.push((HashAlgorithm::SHA1, context.clone().finalize().to_vec()));
+        }
+        if let Some(ref context) = self.sha256 {
+            hashes.push((HashAlgorithm::SHA256, context.clone().finalize().to_vec()));
+        }
+        hashes
+    }
+
+    /// Finish the hashes and store them in the header.  Space for each
+    /// hash is already reserved, so the header does not change length.
+    pub fn store(&self, header: &mut PicoHeader) -> Result<()> {
+        for (algorithm, hash) in self.compute() {
+            match algorithm.extension_kind() {
+                None => header.hash.copy_from_slice(&hash),
+                Some(kind) => header.set_extension(kind, hash)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish the hashes and compare them to the hashes stored in the
+    /// header.  The first difference is reported as an error.
+    pub fn check(&self, header: &PicoHeader) -> Result<()> {
+        for (algorithm, computed) in self.compute() {
+            let stored = header.get_stored_hash(algorithm).unwrap_or(&[]);
+            if stored != computed.as_slice() {
+                return Err(PicoError::HashMismatch {
+                    algorithm,
+                    stored: stored.to_vec(),
+                    computed,
+                });
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,15 @@
-//! Exporting Pico file header information.
+//! Pico file header parsing, serialization, and export.
 
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
 use std::str::FromStr;
 use std::result;
+use constants::*;
+use errors::{PicoError, Result};
+use intbytes::{ByteDump, dump_vec};
+use crypt::CipherKind;
+use provenance::Provenance;
+use options::PicoOpenOptions;
 
 /// Different formats for writing out the header.
 #[derive(Debug)]
@@ -13,14 +21,21 @@ pub enum HeaderFormat {
     /// ```python
     /// {
     ///     "magic" : [ 0x91, 0xC0 ],
-    ///     "major" : 1,
+    ///     "major" : 2,
     ///     "minor" : 0,
-    ///     "offset" : 42,
+    ///     "offset" : 91,
     ///     "hash" : [ 0xD4, 0x1D, 0x8C, 0xD9, 0x8F, 0x00, 0xB2, 0x04,
     ///                0xE9, 0x80, 0x09, 0x98, 0xEC, 0xF8, 0x42, 0x7E ],
+    ///     "sha256" : [ 0xE3, 0xB0, 0xC4, 0x42, 0x98, 0xFC, 0x1C, 0x14,
+    ///                  0x9A, 0xFB, 0xF4, 0xC8, 0x99, 0x6F, 0xB9, 0x24,
+    ///                  0x27, 0xAE, 0x41, 0xE4, 0x64, 0x9B, 0x93, 0x4C,
+    ///                  0xA4, 0x95, 0x99, 0x1B, 0x78, 0x52, 0xB8, 0x55 ],
     ///     "key_length" : 4,
     ///     "key" : [ 0x55, 0x21, 0xE4, 0x9A ],
     ///     "md_length" : 10,
+    ///     "extensions" : [
+    ///         { "type" : 32768, "value" : [ 0x2A ] },
+    ///     ],
     /// }
     /// ```
     DICT,
@@ -30,14 +45,21 @@ pub enum HeaderFormat {
     /// ```json
     /// {
     ///     "magic" : [ 145, 192 ],
-    ///     "major" : 1,
+    ///     "major" : 2,
     ///     "minor" : 0,
-    ///     "offset" : 42,
+    ///     "offset" : 91,
     ///     "hash" : [ 212, 29, 140, 217, 143, 0, 178, 4,
     ///                233, 128, 9, 152, 236, 248, 66, 126 ],
+    ///     "sha256" : [ 227, 176, 196, 66, 152, 252, 28, 20,
+    ///                  154, 251, 244, 200, 153, 111, 185, 36,
+    ///                  39, 174, 65, 228, 100, 155, 147, 76,
+    ///                  164, 149, 153, 27, 120, 82, 184, 85 ],
     ///     "key_length" : 4,
     ///     "key" : [ 85, 33, 228, 154 ],
     ///     "md_length" : 10,
+    ///     "extensions" : [
+    ///         { "type" : 32768, "value" : [ 42 ] }
+    ///     ]
     /// }
     /// ```
     JSON,
@@ -47,23 +69,31 @@ pub enum HeaderFormat {
     /// # Example
     /// ```yaml
     /// magic: [ 145, 192 ]
-    /// major: 1
+    /// major: 2
     /// minor: 0
-    /// offset: 42
+    /// offset: 91
     /// hash: [ 212, 29, 140, 217, 143, 0, 178, 4,
     ///         233, 128, 9, 152, 236, 248, 66, 126 ]
+    /// sha256: [ 227, 176, 196, 66, 152, 252, 28, 20,
+    ///           154, 251, 244, 200, 153, 111, 185, 36,
+    ///           39, 174, 65, 228, 100, 155, 147, 76,
+    ///           164, 149, 153, 27, 120, 82, 184, 85 ]
     /// key_length: 4
     /// key: [ 85, 33, 228, 154 ]
     /// md_length: 10
+    /// extensions:
+    ///   - type: 32768
+    ///     value: [ 42 ]
     /// ```
     YAML,
     /// Use XML format.  All data is part of a single element, with data
-    /// values provided by attributes.
+    /// values provided by attributes.  Header extensions, other than hashes,
+    /// are child elements.
     ///
     /// # Example
     /// ```xml
     /// <pico magic='0x91C0' major='1' minor='0' offset='42'
-    ///       hash='D41D8CD98F00B204E9800998ECF8427E key='5521E49A
+    ///       hash='D41D8CD98F00B204E9800998ECF8427E' key='5521E49A'
     ///       md_length='10' />
     /// ```
     XML,
@@ -84,3 +114,837 @@ impl FromStr for HeaderFormat {
         }
     }
 }
+
+
+/// Algorithms used to hash the data.
+///
+/// The MD5 hash is always stored in the fixed part of the header.  Other
+/// hashes are stored in header extensions, and so require version 2.0 or
+/// later of the encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// MD5, always computed.
+    MD5,
+    /// SHA-1, stored in an extension of type `EXT_SHA1`.
+    SHA1,
+    /// SHA-256, stored in an extension of type `EXT_SHA256`.
+    SHA256,
+}
+
+impl HashAlgorithm {
+    /// Get the extension type used to store this hash, or `None` for MD5.
+    pub fn extension_kind(&self) -> Option<u16> {
+        match *self {
+            HashAlgorithm::MD5 => None,
+            HashAlgorithm::SHA1 => Some(EXT_SHA1),
+            HashAlgorithm::SHA256 => Some(EXT_SHA256),
+        }
+    }
+
+    /// Get the length, in bytes, of this hash.
+    pub fn hash_len(&self) -> usize {
+        match *self {
+            HashAlgorithm::MD5 => HASH_LEN,
+            HashAlgorithm::SHA1 => SHA1_LEN,
+            HashAlgorithm::SHA256 => SHA256_LEN,
+        }
+    }
+
+    /// Get the name used for this hash in header dumps.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::MD5 => "hash",
+            HashAlgorithm::SHA1 => "sha1",
+            HashAlgorithm::SHA256 => "sha256",
+        }
+    }
+}
+
+/// Hashes stored in extensions, in the order they are dumped.
+const EXTENSION_HASHES: [HashAlgorithm; 2] = [HashAlgorithm::SHA1, HashAlgorithm::SHA256];
+
+/// A typed entry in the header extension area.
+///
+/// Extensions are stored as a type, a length, and a value.  Readers keep
+/// extensions of unknown type so that they survive rewriting the header,
+/// but otherwise ignore them.  Types `EXT_SHA1` and `EXT_SHA256` hold
+/// hashes of the data, and type `EXT_CIPHER` holds the cipher identifier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    /// The type of the extension.
+    pub kind: u16,
+    /// The value of the extension.
+    pub value: Vec<u8>,
+}
+
+/// The header of a Pico-encoded file.
+///
+/// The header can be parsed from and serialized to any byte stream, so it
+/// can be inspected or constructed without an open Pico file.  The
+/// metadata and data that follow the header are not part of it.
+///
+/// Starting with version 2.0 of the encoding, the key is followed by an
+/// extension area holding typed entries.  Version 1.0 headers have no
+/// extension area, and can still be created with `new_version`.
+///
+/// ```
+/// use pico::PicoHeader;
+///
+/// let header = PicoHeader::new(vec![0x55, 0x21, 0xe4, 0x9a], 10).unwrap();
+/// let mut bytes: Vec<u8> = Vec::new();
+/// header.write_to(&mut bytes).unwrap();
+/// assert_eq!(bytes.len(), header.md_start());
+/// assert_eq!(PicoHeader::parse(&bytes).unwrap(), header);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PicoHeader {
+    /// The magic number.
+    pub magic: u16,
+    /// Major version number of the encoding.
+    pub major: u16,
+    /// Minor version number of the encoding.
+    pub minor: u16,
+    /// Zero-based offset to the start of data.
+    pub offset: u32,
+    /// The MD5 hash of the data.
+    pub hash: [u8; HASH_LEN],
+    /// The encryption key.
+    pub key: Vec<u8>,
+    /// Header extensions.  Always empty for version 1.
+    pub extensions: Vec<Extension>,
+    /// The number of bytes reserved for metadata.
+    pub md_length: u32,
+}
+
+impl PicoHeader {
+    /// Create a header for a new file using the version of the encoding
+    /// implemented by this library.  The hash is zero.  If the metadata
+    /// length is too large for the offset to fit in 32 bits, an error is
+    /// returned.
+    ///
+    /// # Arguments
+    /// * `key`       - The encryption key to use.
+    /// * `md_length` - The number of bytes to reserve for metadata.
+    pub fn new(key: Vec<u8>, md_length: u32) -> Result<PicoHeader> {
+        PicoHeader::new_version(MAJOR, MINOR, key, md_length)
+    }
+
+    /// Create a header for a new file using a specific version of the
+    /// encoding, such as 1.0 for compatibility with older readers.  The
+    /// hash is zero.
+    ///
+    /// # Arguments
+    /// * `major`     - The major version number, from 1 to `MAJOR`.
+    /// * `minor`     - The minor version number.
+    /// * `key`       - The encryption key to use.
+    /// * `md_length` - The number of bytes to reserve for metadata.
+    pub fn new_version(major: u16, minor: u16, key: Vec<u8>, md_length: u32)
+        -> Result<PicoHeader> {
+        if major == 0 || major > MAJOR {
+            return Err(PicoError::BadVersion(major, minor));
+        }
+        let mut header = PicoHeader {
+            magic: MAGIC,
+            major,
+            minor,
+            offset: 0,
+            hash: [0; HASH_LEN],
+            key,
+            extensions: Vec::new(),
+            md_length,
+        };
+        header.update_offset()?;
+        Ok(header)
+    }
+
+    /// Check that a key can be used.  Keys must have between 1 and 65535
+    /// bytes, since the length is stored in two bytes and the cipher
+    /// needs at least one byte.
+    pub fn check_key(key: &[u8]) -> Result<()> {
+        if key.is_empty() || key.len() > u16::MAX as usize {
+            return Err(PicoError::KeyError);
+        }
+        Ok(())
+    }
+
+    /// Check that a file of the given length holds everything up to the
+    /// start of the data.  If not, then `PicoError::Truncated` is returned.
+    pub fn check_length(&self, length: u64) -> Result<()> {
+        if length < self.offset as u64 {
+            return Err(PicoError::Truncated(self.offset as u64));
+        }
+        Ok(())
+    }
+
+    /// Determine whether this version of the header has an extension area.
+    pub fn has_extension_area(&self) -> bool {
+        self.major >= EXTENSIONS_MAJOR
+    }
+
+    /// Get the number of bytes used by the extension entries, not counting
+    /// the extension area length field.
+    pub fn extensions_length(&self) -> usize {
+        self.extensions.iter()
+            .map(|ext| EXT_TYPE_LEN + EXT_LENGTH_LEN + ext.value.len())
+            .sum()
+    }
+
+    /// Get the zero-based offset of the start of the metadata, which is
+    /// also the number of bytes in the serialized header.
+    pub fn md_start(&self) -> usize {
+        if self.has_extension_area() {
+            KEY_POS + self.key.len() + EXTLEN_LEN + self.extensions_length()
+        } else {
+            KEY_POS + self.key.len()
+        }
+    }
+
+    /// Set the offset to the data to immediately follow the metadata.
+    /// This must be done after changing the key, extensions, or metadata
+    /// length.  If the offset does not fit in 32 bits, it is not changed
+    /// and an error is returned.
+    pub fn update_offset(&mut self) -> Result<()> {
+        let md_start = u32::try_from(self.md_start()).map_err(
+            |_| PicoError::BadOffset(self.offset, u32::MAX),
+        )?;
+        self.offset = md_start.checked_add(self.md_length).ok_or(
+            PicoError::MetadataTooLarge(self.md_length, u32::MAX - md_start),
+        )?;
+        Ok(())
+    }
+
+    /// Get the value of the first extension of the given type, if any.
+    pub fn get_extension(&self, kind: u16) -> Option<&[u8]> {
+        self.extensions.iter()
+            .find(|ext| ext.kind == kind)
+            .map(|ext| ext.value.as_slice())
+    }
+
+    /// Set the value of an extension, replacing any existing extension of
+    /// the same type, and update the offset.  Version 1 headers cannot
+    /// hold extensions.
+    pub fn set_extension(&mut self, kind: u16, value: Vec<u8>) -> Result<()> {
+        if !self.has_extension_area() {
+            return Err(PicoError::ExtensionsNotSupported(self.major, self.minor));
+        }
+        match self.extensions.iter_mut().find(|ext| ext.kind == kind) {
+            Some(ext) => ext.value = value,
+            None => self.extensions.push(Extension { kind, value }),
+        }
+        self.update_offset()
+    }
+
+    /// Remove any extensions of the given type, and update the offset.
+    pub fn remove_extension(&mut self, kind: u16) -> Result<()> {
+        self.extensions.retain(|ext| ext.kind != kind);
+        self.update_offset()
+    }
+
+    /// Get the cipher used to encrypt the data.  If the header does not
+    /// record a cipher, then the data is encrypted with xor.
+    pub fn get_cipher(&self) -> Result<CipherKind> {
+        match self.get_extension(EXT_CIPHER) {
+            None => Ok(CipherKind::XOR),
+            Some(value) => {
+                if value.len() != 2 {
+                    return Err(PicoError::BadExtension(value.len() as u32));
+                }
+                let id = ((value[0] as u16) << 8) | (value[1] as u16);
+                CipherKind::from_id(id).ok_or(PicoError::UnknownCipher(id))
+            }
+        }
+    }
+
+    /// Set the cipher used to encrypt the data, and update the offset.
+    /// This must be done before any metadata or data is written.  Version
+    /// 1 headers can only use xor.
+    pub fn set_cipher(&mut self, cipher: CipherKind) -> Result<()> {
+        if cipher == CipherKind::XOR {
+            return self.remove_extension(EXT_CIPHER);
+        }
+        self.set_extension(EXT_CIPHER, cipher.id().get_bytes().to_vec())
+    }
+
+    /// Store an additional hash of the data in this header.  Space for the
+    /// hash is reserved in the extension area, and the offset is updated,
+    /// so this must be done before any metadata or data is written.  The
+    /// hash itself is computed when the file is flushed.  Version 1
+    /// headers can only hold the MD5 hash.
+    pub fn enable_hash(&mut self, algorithm: HashAlgorithm) -> Result<()> {
+        match algorithm.extension_kind() {
+            None => Ok(()),
+            Some(kind) => {
+                if self.get_extension(kind).is_some() {
+                    return Ok(());
+                }
+                self.set_extension(kind, vec![0; algorithm.hash_len()])
+            }
+        }
+    }
+
+    /// Get the stored hash of the data for the given algorithm, if this
+    /// header holds one.
+    pub fn get_stored_hash(&self, algorithm: HashAlgorithm) -> Option<&[u8]> {
+        match algorithm.extension_kind() {
+            None => Some(&self.hash),
+            Some(kind) => self.get_extension(kind)
+                .filter(|value| value.len() == algorithm.hash_len()),
+        }
+    }
+
+    /// Get the cipher recorded in the header, if there is one and it is
+    /// known.
+    fn known_cipher(&self) -> Option<CipherKind> {
+        self.get_extension(EXT_CIPHER).and_then(|_| self.get_cipher().ok())
+    }
+
+    /// Get the extensions that are not dumped as named fields.
+    fn other_extensions(&self) -> Vec<&Extension> {
+        let cipher = self.known_cipher().is_some();
+        self.extensions.iter()
+            .filter(|ext| ext.kind != EXT_SHA1 && ext.kind != EXT_SHA256)
+            .filter(|ext| !(cipher && ext.kind == EXT_CIPHER))
+            .collect()
+    }
+
+    /// Parse a header from the start of a byte slice.  Any bytes after the
+    /// header are ignored.
+    pub fn parse(bytes: &[u8]) -> Result<PicoHeader> {
+        let mut source = bytes;
+        PicoHeader::read_from(&mut source)
+    }
+
+    /// Read a header from a stream, with the default limits of
+    /// `PicoOpenOptions`.  The stream must be positioned at the start of the
+    /// header, and is left positioned at the end of the header, which is
+    /// the start of the metadata.
+    pub fn read_from<R: Read>(source: &mut R) -> Result<PicoHeader> {
+        PicoHeader::read_limited(source, &PicoOpenOptions::new())
+    }
+
+    /// Read a header from a stream, applying the given limits.  Nothing is
+    /// allocated for a field until its length has been checked against the
+    /// limits.  If the stream ends within the header, then
+    /// `PicoError::Truncated` is returned.
+    pub(crate) fn read_limited<R: Read>(source: &mut R, options: &PicoOpenOptions)
+        -> Result<PicoHeader> {
+        // Read exactly enough bytes to fill the buffer, which ends at the
+        // given position in the file.  Positions are u64, so that no sum
+        // of header fields can overflow.
+        fn read_field<R: Read>(source: &mut R, buf: &mut [u8], end: u64, id: u32) -> Result<()> {
+            source.read_exact(buf).map_err(|err| {
+                if err.kind() == ErrorKind::UnexpectedEof {
+                    PicoError::Truncated(end)
+                } else {
+                    PicoError::ReadFailed(id, err)
+                }
+            })
+        }
+        fn read_u16<R: Read>(source: &mut R, end: u64, id: u32) -> Result<u16> {
+            let mut buf = [0u8; 2];
+            read_field(source, &mut buf, end, id)?;
+            Ok(((buf[0] as u16) << 8) | (buf[1] as u16))
+        }
+        fn read_u32<R: Read>(source: &mut R, end: u64, id: u32) -> Result<u32> {
+            let mut buf = [0u8; 4];
+            read_field(source, &mut buf, end, id)?;
+            Ok(((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) |
+                (buf[3] as u32))
+        }
+
+        // Read the magic number.
+        let magic = read_u16(source, MAJOR_POS as u64, 1002)?;
+        if magic != MAGIC {
+            return Err(PicoError::NotPico(magic));
+        }
+
+        // Read the version numbers.
+        let major = read_u16(source, MINOR_POS as u64, 1003)?;
+        let minor = read_u16(source, OFFSET_POS as u64, 1004)?;
+        if major == 0 || major > MAJOR {
+            return Err(PicoError::BadVersion(major, minor));
+        }
+
+        // Read the offset.
+        let offset = read_u32(source, HASH_POS as u64, 1005)?;
+
+        // Read the hash.
+        let mut hash = [0u8; HASH_LEN];
+        read_field(source, &mut hash, KEYLEN_POS as u64, 1006)?;
+
+        // Read the key length and then the key.
+        let keylen = read_u16(source, KEY_POS as u64, 1007)?;
+        if keylen == 0 {
+            return Err(PicoError::KeyError);
+        }
+        if keylen > options.max_key_length {
+            return Err(PicoError::LimitExceeded(
+                "key", keylen as u64, options.max_key_length as u64));
+        }
+        let mut md_start = KEY_POS as u64 + keylen as u64;
+        let mut key = vec![0u8; keylen as usize];
+        read_field(source, &mut key, md_start, 1008)?;
+
+        // Read the extension area, if this version has one.
+        let mut extensions = Vec::new();
+        if major >= EXTENSIONS_MAJOR {
+            md_start += EXTLEN_LEN as u64;
+            let extlen = read_u32(source, md_start, 1028)?;
+            if extlen > options.max_extensions_length {
+                return Err(PicoError::LimitExceeded(
+                    "extension area", extlen as u64, options.max_extensions_length as u64));
+            }
+            md_start += extlen as u64;
+            let mut area = vec![0u8; extlen as usize];
+            read_field(source, &mut area, md_start, 1029)?;
+            extensions = parse_extensions(&area)?;
+        }
+
+        // Compute the metadata length.
+        let md_length = match (offset as u64).checked_sub(md_start) {
+            Some(length) => length as u32,
+            None => return Err(PicoError::BadOffset(offset, md_start.min(u32::MAX as u64) as u32)),
+        };
+        if md_length > options.max_metadata_length {
+            return Err(PicoError::LimitExceeded(
+                "metadata", md_length as u64, options.max_metadata_length as u64));
+        }
+
+        // Done.
+        Ok(PicoHeader {
+            magic,
+            major,
+            minor,
+            offset,
+            hash,
+            key,
+            extensions,
+            md_length,
+        })
+    }
+
+    /// Write the header to a stream.
+    pub fn write_to<W: Write>(&self, target: &mut W) -> Result<()> {
+        // Write the magic number.
+        target.write_all(&self.magic.get_bytes()).map_err(
+            |err| PicoError::WriteFailed(1019, err),
+        )?;
+
+        // Write the version number.
+        target.write_all(&self.major.get_bytes()).map_err(
+            |err| PicoError::WriteFailed(1020, err),
+        )?;
+        target.write_all(&self.minor.get_bytes()).map_err(
+            |err| PicoError::WriteFailed(1021, err),
+        )?;
+
+        // Write the offset to the data.
+        target.write_all(&self.offset.get_bytes()).map_err(
+            |err| PicoError::WriteFailed(1022, err),
+        )?;
+
+        // Write the hash.
+        target.write_all(&self.hash).map_err(
+            |err| PicoError::WriteFailed(1023, err),
+        )?;
+
+        // Write the key length and then the key.
+        target.write_all(&(self.key.len() as u16).get_bytes()).map_err(
+            |err| PicoError::WriteFailed(1024, err),
+        )?;
+        target.write_all(&self.key).map_err(
+            |err| PicoError::WriteFailed(1025, err),
+        )?;
+
+        // Write the extension area, if this version has one.
+        if self.has_extension_area() {
+            let mut area = Vec::with_capacity(EXTLEN_LEN + self.extensions_length());
+            area.extend_from_slice(&(self.extensions_length() as u32).get_bytes());
+            for ext in &self.extensions {
+                area.extend_from_slice(&ext.kind.get_bytes());
+                area.extend_from_slice(&(ext.value.len() as u32).get_bytes());
+                area.extend_from_slice(&ext.value);
+            }
+            target.write_all(&area).map_err(
+                |err| PicoError::WriteFailed(1030, err),
+            )?;
+        } else if !self.extensions.is_empty() {
+            return Err(PicoError::ExtensionsNotSupported(self.major, self.minor));
+        }
+
+        // If we get here, success!
+        Ok(())
+    }
+
+    /// Dump the content of the header in the correct form.
+    ///
+    /// # Arguments
+    /// * `target` - The writer to get the output.
+    /// * `form`   - The format to use to write.
+    pub fn dump<U>(&self, target: &mut U, form: &HeaderFormat)
+    where
+        U: Write,
+    {
+        self.dump_with_provenance(target, form, None)
+    }
+
+    /// Dump the content of the header in the correct form, followed by
+    /// the provenance of the data, if known.
+    ///
+    /// # Arguments
+    /// * `target`     - The writer to get the output.
+    /// * `form`       - The format to use to write.
+    /// * `provenance` - The provenance to include.
+    #[allow(unused_must_use)]
+    pub fn dump_with_provenance<U>(&self, target: &mut U, form: &HeaderFormat,
+                                   provenance: Option<&Provenance>)
+    where
+        U: Write,
+    {
+        let others = self.other_extensions();
+        match *form {
+            HeaderFormat::DICT => {
+                writeln!(target, "{{");
+                write!(target, "    \"magic\" : [ ");
+                self.magic.dump_bytes(target, true);
+                writeln!(target, " ],");
+                writeln!(target, "    \"major\" : {},", self.major);
+                writeln!(target, "    \"minor\" : {},", self.minor);
+                writeln!(target, "    \"offset\" : {},", self.offset);
+                write!(target, "    \"hash\" : [ ");
+                dump_vec(target, &self.hash, true, true);
+                writeln!(target, " ],");
+                for algorithm in &EXTENSION_HASHES {
+                    if let Some(hash) = self.get_stored_hash(*algorithm) {
+                        write!(target, "    \"{}\" : [ ", algorithm.name());
+                        dump_vec(target, hash, true, true);
+                        writeln!(target, " ],");
+                    }
+                }
+                writeln!(target, "    \"key_length\" : {},", self.key.len());
+                write!(target, "    \"key\" : [ ");
+                dump_vec(target, &self.key, true, true);
+                writeln!(target, " ],");
+                writeln!(target, "    \"md_length\" : {},", self.md_length);
+                if let Some(cipher) = self.known_cipher() {
+                    writeln!(target, "    \"cipher\" : \"{:?}\",", cipher);
+                }
+                if let Some(provenance) = provenance {
+                    provenance.dump(target, form);
+                }
+                writeln!(target, "    \"extensions\" : [");
+                for ext in &others {
+                    write!(target, "        {{ \"type\" : {}, \"value\" : [ ", ext.kind);
+                    dump_vec(target, &ext.value, true, true);
+                    writeln!(target, " ] }},");
+                }
+                writeln!(target, "    ],");
+                writeln!(target, "}}");
+            }
+            HeaderFormat::JSON => {
+                writeln!(target, "{{");
+                write!(target, "    \"magic\" : [ ");
+                self.magic.dump_bytes(target, false);
+                writeln!(target, " ],");
+                writeln!(target, "    \"major\" : {},", self.major);
+                writeln!(target, "    \"minor\" : {},", self.minor);
+                writeln!(target, "    \"offset\" : {},", self.offset);
+                write!(target, "    \"hash\" : [ ");
+                dump_vec(target, &self.hash, false, true);
+                writeln!(target, " ],");
+                for algorithm in &EXTENSION_HASHES {
+                    if let Some(hash) = self.get_stored_hash(*algorithm) {
+                        write!(target, "    \"{}\" : [ ", algorithm.name());
+                        dump_vec(target, hash, false, true);
+                        writeln!(target, " ],");
+                    }
+                }
+                writeln!(target, "    \"key_length\" : {},", self.key.len());
+                write!(target, "    \"key\" : [ ");
+                dump_vec(target, &self.key, false, true);
+                writeln!(target, " ],");
+                writeln!(target, "    \"md_length\" : {},", self.md_length);
+                if let Some(cipher) = self.known_cipher() {
+                    writeln!(target, "    \"cipher\" : \"{:?}\",", cipher);
+                }
+                if let Some(provenance) = provenance {
+                    provenance.dump(target, form);
+                }
+                write!(target, "    \"extensions\" : [");
+                let mut first = true;
+                for ext in &others {
+                    writeln!(target, "{}", if first { "" } else { "," });
+                    first = false;
+                    write!(target, "        {{ \"type\" : {}, \"value\" : [ ", ext.kind);
+                    dump_vec(target, &ext.value, false, true);
+                    write!(target, " ] }}");
+                }
+                writeln!(target, "{}]", if first { " " } else { "\n    " });
+                writeln!(target, "}}");
+            }
+            HeaderFormat::YAML => {
+                write!(target, "magic: [ ");
+                self.magic.dump_bytes(target, false);
+                writeln!(target, " ]");
+                writeln!(target, "major: {}", self.major);
+                writeln!(target, "minor: {}", self.minor);
+                writeln!(target, "offset: {}", self.offset);
+                write!(target, "hash: [ ");
+                dump_vec(target, &self.hash, false, true);
+                writeln!(target, " ]");
+                for algorithm in &EXTENSION_HASHES {
+                    if let Some(hash) = self.get_stored_hash(*algorithm) {
+                        write!(target, "{}: [ ", algorithm.name());
+                        dump_vec(target, hash, false, true);
+                        writeln!(target, " ]");
+                    }
+                }
+                writeln!(target, "key_length: {}", self.key.len());
+                write!(target, "key: [ ");
+                dump_vec(target, &self.key, false, true);
+                writeln!(target, " ]");
+                writeln!(target, "md_length: {}", self.md_length);
+                if let Some(cipher) = self.known_cipher() {
+                    writeln!(target, "cipher: {:?}", cipher);
+                }
+                if let Some(provenance) = provenance {
+                    provenance.dump(target, form);
+                }
+                if others.is_empty() {
+                    writeln!(target, "extensions: [ ]");
+                } else {
+                    writeln!(target, "extensions:");
+                    for ext in &others {
+                        writeln!(target, "  - type: {}", ext.kind);
+                        write!(target, "    value: [ ");
+                        dump_vec(target, &ext.value, false, true);
+                        writeln!(target, " ]");
+                    }
+                }
+            }
+            HeaderFormat::XML => {
+                write!(
+                    target,
+                    r#"<pico magic='0x{:04X}' major='{}' minor='{}' offset='{}'"#,
+                    self.magic,
+                    self.major,
+                    self.minor,
+                    self.offset
+                );
+                write!(target, " hash='");
+                dump_vec(target, &self.hash, true, false);
+                write!(target, "'");
+                for algorithm in &EXTENSION_HASHES {
+                    if let Some(hash) = self.get_stored_hash(*algorithm) {
+                        write!(target, " {}='", algorithm.name());
+                        dump_vec(target, hash, true, false);
+                        write!(target, "'");
+                    }
+                }
+                write!(target, " key='");
+                dump_vec(target, &self.key, true, false);
+                write!(target, "'");
+                write!(target, " md_length='{}'", self.md_length);
+                if let Some(cipher) = self.known_cipher() {
+                    write!(target, " cipher='{:?}'", cipher);
+                }
+                if others.is_empty() && provenance.is_none() {
+                    write!(target, " />");
+                } else {
+                    write!(target, ">");
+                    if let Some(provenance) = provenance {
+                        provenance.dump(target, form);
+                    }
+                    for ext in &others {
+                        write!(target, "<extension type='{}' value='", ext.kind);
+                        dump_vec(target, &ext.value, true, false);
+                        write!(target, "' />");
+                    }
+                    write!(target, "</pico>");
+                }
+            }
+        }
+    }
+}
+
+/// Parse the entries of an extension area.
+fn parse_extensions(area: &[u8]) -> Result<Vec<Extension>> {
+    let mut extensions = Vec::new();
+    let mut rest = area;
+    while !rest.is_empty() {
+        if rest.len() < EXT_TYPE_LEN + EXT_LENGTH_LEN {
+            return Err(PicoError::BadExtension(area.len() as u32));
+        }
+        let kind = ((rest[0] as u16) << 8) | (rest[1] as u16);
+        let length = ((rest[2] as usize) << 24) | ((rest[3] as usize) << 16) |
+            ((rest[4] as usize) << 8) | (rest[5] as usize);
+        rest = &rest[EXT_TYPE_LEN + EXT_LENGTH_LEN..];
+        if rest.len() < length {
+            return Err(PicoError::BadExtension(area.len() as u32));
+        }
+        extensions.push(Extension { kind, value: rest[..length].to_vec() });
+        rest = &rest[length..];
+    }
+    Ok(extensions)
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::{PicoHeader, HeaderFormat, HashAlgorithm};
+    use constants::EXT_CIPHER;
+    use crypt::CipherKind;
+    use errors::PicoError;
+
+    #[test]
+    fn header_test_1() {
+        let mut header = PicoHeader::new_version(1, 0, vec![0x55, 0x21, 0xe4, 0x9a], 10).unwrap();
+        header.hash = [0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04,
+            0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8, 0x42, 0x7e];
+        assert_eq!(header.offset, 42);
+        let mut bytes: Vec<u8> = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes, vec![
+            0x91, 0xc0, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a,
+            0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04,
+            0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8, 0x42, 0x7e,
+            0x00, 0x04, 0x55, 0x21, 0xe4, 0x9a]);
+        bytes.extend_from_slice(b"Martindale");
+        assert_eq!(PicoHeader::parse(&bytes).unwrap(), header);
+    }
+
+    #[test]
+    fn header_test_2() {
+        let header = PicoHeader::new(vec![0x55, 0x21, 0xe4, 0x9a], 10).unwrap();
+        let mut bytes: Vec<u8> = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        match PicoHeader::parse(&bytes[..20]) {
+            Err(PicoError::Truncated(26)) => (),
+            _ => panic!("Expected a truncated header."),
+        }
+        bytes[0] = 0x19;
+        match PicoHeader::parse(&bytes) {
+            Err(PicoError::NotPico(0x19c0)) => (),
+            _ => panic!("Expected a bad magic number."),
+        }
+    }
+
+    #[test]
+    fn header_test_3() {
+        let header = PicoHeader::new_version(1, 0, vec![0x55, 0x21, 0xe4, 0x9a], 10).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        header.dump(&mut output, &HeaderFormat::XML);
+        assert_eq!(String::from_utf8(output).unwrap(),
+            "<pico magic='0x91C0' major='1' minor='0' offset='42' \
+            hash='00000000000000000000000000000000' key='5521E49A' md_length='10' />");
+    }
+
+    #[test]
+    fn header_test_4() {
+        let mut header = PicoHeader::new(vec![0x55, 0x21, 0xe4, 0x9a], 10).unwrap();
+        assert_eq!(header.major, 2);
+        assert_eq!(header.offset, 46);
+        header.set_extension(0x7777, vec![1, 2, 3]).unwrap();
+        header.set_extension(0x7778, vec![]).unwrap();
+        header.set_extension(0x7777, vec![4, 5]).unwrap();
+        assert_eq!(header.offset, 46 + 8 + 6);
+        assert_eq!(header.get_extension(0x7777), Some(&[4u8, 5][..]));
+        let mut bytes: Vec<u8> = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        assert_eq!(&bytes[32..], &[
+            0x00, 0x00, 0x00, 0x0e,
+            0x77, 0x77, 0x00, 0x00, 0x00, 0x02, 0x04, 0x05,
+            0x77, 0x78, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(bytes.len(), header.md_start());
+        assert_eq!(PicoHeader::parse(&bytes).unwrap(), header);
+
+        // An entry that overruns the extension area is rejected.
+        bytes[35] = 0x0d;
+        match PicoHeader::parse(&bytes) {
+            Err(PicoError::BadExtension(13)) => (),
+            _ => panic!("Expected a bad extension."),
+        }
+    }
+
+    #[test]
+    fn header_test_5() {
+        let mut header = PicoHeader::new_version(1, 0, vec![0x55], 0).unwrap();
+        match header.set_extension(1, vec![1]) {
+            Err(PicoError::ExtensionsNotSupported(1, 0)) => (),
+            _ => panic!("Expected extensions to be rejected."),
+        }
+        assert!(PicoHeader::new_version(3, 0, vec![0x55], 0).is_err());
+        match PicoHeader::new(vec![0x55], u32::MAX) {
+            Err(PicoError::MetadataTooLarge(u32::MAX, _)) => (),
+            _ => panic!("Expected the metadata to be too large."),
+        }
+        header.extensions.push(super::Extension { kind: 1, value: vec![1] });
+        assert!(header.write_to(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn header_test_6() {
+        let mut header = PicoHeader::new(vec![0x55], 0).unwrap();
+        header.set_extension(0x8000, vec![42]).unwrap();
+        header.set_extension(0x8001, vec![]).unwrap();
+        header.enable_hash(HashAlgorithm::SHA1).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        header.dump(&mut output, &HeaderFormat::JSON);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with("    \"md_length\" : 0,\n    \"extensions\" : [\n        \
+            { \"type\" : 32768, \"value\" : [ 42 ] },\n        \
+            { \"type\" : 32769, \"value\" : [  ] }\n    ]\n}\n"));
+        let mut output: Vec<u8> = Vec::new();
+        header.dump(&mut output, &HeaderFormat::XML);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with("md_length='0'><extension type='32768' value='2A' />\
+            <extension type='32769' value='' /></pico>"));
+        assert!(output.contains(" sha1='0000000000000000000000000000000000000000' key='55'"));
+    }
+
+    #[test]
+    fn header_test_7() {
+        let mut header = PicoHeader::new(vec![0x55], 0).unwrap();
+        header.enable_hash(HashAlgorithm::SHA256).unwrap();
+        header.enable_hash(HashAlgorithm::SHA256).unwrap();
+        assert_eq!(header.extensions.len(), 1);
+        assert_eq!(header.offset, 29 + 4 + 6 + 32);
+        assert_eq!(header.get_stored_hash(HashAlgorithm::SHA256), Some(&[0u8; 32][..]));
+        assert_eq!(header.get_stored_hash(HashAlgorithm::SHA1), None);
+        let mut output: Vec<u8> = Vec::new();
+        header.dump(&mut output, &HeaderFormat::YAML);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\nsha256: [ 0, 0, "));
+        assert!(output.ends_with("extensions: [ ]\n"));
+
+        let mut header = PicoHeader::new_version(1, 0, vec![0x55], 0).unwrap();
+        header.enable_hash(HashAlgorithm::MD5).unwrap();
+        assert!(header.enable_hash(HashAlgorithm::SHA256).is_err());
+    }
+
+    #[test]
+    fn header_test_8() {
+        let mut header = PicoHeader::new(vec![0x55], 0).unwrap();
+        assert_eq!(header.get_cipher().unwrap(), CipherKind::XOR);
+        header.set_cipher(CipherKind::CHACHA20).unwrap();
+        assert_eq!(header.get_cipher().unwrap(), CipherKind::CHACHA20);
+        assert_eq!(header.offset, 29 + 4 + 8);
+        let mut output: Vec<u8> = Vec::new();
+        header.dump(&mut output, &HeaderFormat::XML);
+        assert!(String::from_utf8(output).unwrap().ends_with(" md_length='0' cipher='CHACHA20' />"));
+        header.set_cipher(CipherKind::XOR).unwrap();
+        assert!(header.extensions.is_empty());
+        assert_eq!(header.offset, 29 + 4);
+
+        // Unknown ciphers are reported, and dumped as plain extensions.
+        header.set_extension(EXT_CIPHER, vec![0x12, 0x34]).unwrap();
+        match header.get_cipher() {
+            Err(PicoError::UnknownCipher(0x1234)) => (),
+            _ => panic!("Expected an unknown cipher."),
+        }
+        let mut output: Vec<u8> = Vec::new();
+        header.dump(&mut output, &HeaderFormat::YAML);
+        assert!(String::from_utf8(output).unwrap().ends_with("  - type: 3\n    value: [ 18, 52 ]\n"));
+        assert!(PicoHeader::new_version(1, 0, vec![0x55], 0).unwrap()
+            .set_cipher(CipherKind::CHACHA20).is_err());
+    }
+}
@@ -37,7 +37,7 @@ pub trait ByteDump {
 
 impl ByteDump for u8 {
     fn get_bytes(&self) -> Box<[u8]> {
-        let arr: [u8; 1] = [*self as u8];
+        let arr: [u8; 1] = [*self];
         Box::new(arr)
     }
 }
@@ -92,7 +92,7 @@ impl ByteDump for u64 {
 /// * `hex`    - If true, print the numbers in hexadecmial.
 /// * `commas` - If true, print commas between numbers.
 #[allow(unused_must_use)]
-pub fn dump_vec<U: io::Write>(target: &mut U, bytes: &Vec<u8>, hex: bool, commas: bool) {
+pub fn dump_vec<U: io::Write>(target: &mut U, bytes: &[u8], hex: bool, commas: bool) {
     let mut first = true;
     for byte in bytes {
         if (!hex) || commas {
@@ -124,7 +124,7 @@ mod test {
     #[inline]
     fn dump_u8() {
         let mut output: Vec<u8> = Vec::new();
-        (0x49 as u8).dump_bytes(&mut output, true);
+        0x49u8.dump_bytes(&mut output, true);
         assert_eq!(output, Vec::<u8>::from("0x49"));
     }
 
@@ -132,7 +132,7 @@ mod test {
     #[inline]
     fn dump_u16() {
         let mut output: Vec<u8> = Vec::new();
-        (0x7c84 as u16).dump_bytes(&mut output, true);
+        0x7c84u16.dump_bytes(&mut output, true);
         assert_eq!(output, Vec::<u8>::from("0x7C, 0x84"));
     }
 
@@ -140,7 +140,7 @@ mod test {
     #[inline]
     fn dump_u32() {
         let mut output: Vec<u8> = Vec::new();
-        (0x4acba5b as u32).dump_bytes(&mut output, true);
+        0x4acba5bu32.dump_bytes(&mut output, true);
         assert_eq!(output, Vec::<u8>::from("0x04, 0xAC, 0xBA, 0x5B"));
     }
 
@@ -148,7 +148,7 @@ mod test {
     #[inline]
     fn dump_u64() {
         let mut output: Vec<u8> = Vec::new();
-        (0x04ACBA5B0055ff23 as u64).dump_bytes(&mut output, true);
+        0x04AC_BA5B_0055_FF23u64.dump_bytes(&mut output, true);
         assert_eq!(
             output,
             Vec::<u8>::from("0x04, 0xAC, 0xBA, 0x5B, 0x00, 0x55, 0xFF, 0x23")
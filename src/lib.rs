@@ -5,9 +5,14 @@
 
 extern crate md5;
 extern crate rand;
+extern crate sha1;
+extern crate sha2;
+extern crate chacha20;
+#[macro_use]
+extern crate log;
+extern crate tempfile;
 
 #[warn(missing_docs)]
-
 pub mod constants;
 pub mod errors;
 mod pico;
@@ -15,10 +20,28 @@ pub mod file;
 mod crypt;
 mod intbytes;
 mod header;
+mod hashes;
+pub mod stream;
+mod memory;
+pub mod shared;
+mod setlen;
+mod metadata;
+mod provenance;
+mod builder;
+mod options;
 pub use pico::Pico;
-pub use header::HeaderFormat;
+pub use builder::PicoBuilder;
+pub use options::PicoOpenOptions;
+pub use header::{Extension, HashAlgorithm, HeaderFormat, PicoHeader};
+pub use stream::{PicoReader, PicoWriter};
+pub use shared::SharedPicoReader;
+pub use setlen::SetLen;
+pub use metadata::{Metadata, MetadataValue, LEGACY_KEY};
+pub use provenance::Provenance;
+pub use crypt::CipherKind;
 use constants::{MAGIC, MINOR, MAJOR};
 pub use pico::gen_random_key;
+pub use memory::{encode_bytes, decode_bytes};
 
 /// Obtain the Pico magic number.  The "magic number" used at the start of a
 /// file to indicate that it is a Pico-encoded file.
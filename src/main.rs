@@ -5,23 +5,36 @@ extern crate hex;
 
 use std::str::FromStr;
 use std::path::Path;
-use std::io::stdout;
-use pico::{HeaderFormat, major, minor};
-use clap::{Arg, App};
+use std::io::{stdin, stdout};
+use std::process::exit;
+use pico::{HashAlgorithm, HeaderFormat, PicoBuilder, Provenance, major, minor};
+use clap::{Arg, App, ArgMatches};
 use pico::file;
 use hex::FromHex;
 
 /// Executable description.
 static DESCRIPTION: &str =
-"Encode a file as Pico, decode a Pico-encoded file, or dump the header \
-from a Pico-encoded file.";
+"Encode a file as Pico, decode a Pico-encoded file, dump the header \
+from a Pico-encoded file, verify the integrity of a Pico-encoded file, \
+resize the metadata reserved in a Pico-encoded file, or change the key of \
+a Pico-encoded file.";
 
 static LONG_DESCRIPTION: &str =
 "Input files are encoded by default.  If encoding, a .pico extension \
 is added to the file.  If decoding, then the input must be Pico-encoded \
 files, and a .raw extension is added by default.  If dumping the header, \
 the input files must be Pico-encoded files, and the header is sent to \
-standard output in the specified format.
+standard output in the specified format.  If verifying, the input files \
+must be Pico-encoded files, and each is reported as passing or failing.  \
+If resizing the metadata, the input files must be Pico-encoded files, and \
+each is modified to reserve the given number of bytes for metadata.  \
+Existing metadata is kept, up to the new size.  If rekeying, the input \
+files must be Pico-encoded files, and each is re-encrypted with the key \
+given by --key, or a random key if none is given.  The decoded data is \
+never written to disk.
+
+The exit status is non-zero if any file could not be processed or failed \
+verification.
 
 The extension used can be overridden by --extension, which should include \
 the dot.  Any provided suffix (by default there is none) is added to the \
@@ -30,7 +43,105 @@ file's base name.
 The header kinds can be JSON, YAML, DICT (Python), or XML.
 
 Keys must be specified as a list of hexadecimal digits (no spaces).  If \
-no key is specified for encoding, a random key is generated.";
+no key is specified for encoding, a random key is generated.
+
+Large files can be encoded and decoded faster by splitting the work \
+across several threads with --threads.  When encoding, only the \
+encryption is split; the hash is computed on a single thread, which \
+reads the file a second time and limits the speedup.
+
+Files are encoded with the latest version of the encoding.  Use \
+--format-version 1.0 to write files that older readers accept; these have \
+no header extensions.
+
+Only the MD5 hash of the data is stored by default.  Use --hash sha1 or \
+--hash sha256 to also store the SHA-1 or SHA-256 hash.  These need version \
+2.0 of the encoding, and make encoding slower.
+
+When encoding, the provenance of each file is stored in its metadata: the \
+original file name and path, the time it was collected, and the user who \
+submitted it.  Tags, a source URL, and a case ID can be added with --tag, \
+--source, and --case.  The permissions and timestamps of the file are \
+also stored.  Dumped headers include the provenance.
+
+When decoding, the original file name, modification time, and permissions \
+can be restored with --restore-name, --restore-mtime, and --restore-mode.  \
+The executable bits are only restored if --restore-exec is also given.  \
+Nothing is restored when decoding standard input.
+
+If a file is given as a single dash (-), then standard input is encoded or \
+decoded, and the result is written to standard output.  The encoded file \
+is held in a temporary file while this is done, so there must be enough \
+temporary disk space for it.  Progress messages are written to standard \
+error, so they do not mix with the output.";
+
+/// Obtain the key to use for encoding.  If the user specified a key, it is
+/// decoded from hexadecimal.  Otherwise a random key is generated.
+fn parse_key(hex: Option<&str>) -> Result<Vec<u8>, String> {
+    match hex {
+        None => Ok(pico::gen_random_key(16)),
+        Some(hex) => {
+            let hex = hex.to_uppercase().into_bytes();
+            let hexlen = hex.len();
+            if hexlen % 2 != 0 {
+                // I think this is more helpful than the default given
+                // by the hex package.
+                return Err("Key must be an even number of hex digits.".to_string());
+            }
+            if hexlen == 0 {
+                // The hex package permits an empth string, so we have
+                // to trap this here.
+                return Err("Key cannot be empty.".to_string());
+            }
+            Vec::<u8>::from_hex(hex).map_err(|err| err.to_string())
+        }
+    }
+}
+
+/// Parse a version of the encoding, given as the major and minor version
+/// numbers separated by a dot.
+fn parse_version(version: &str) -> Result<(u16, u16), String> {
+    let mut parts = version.splitn(2, '.');
+    let major = parts.next().unwrap_or("").parse::<u16>();
+    let minor = parts.next().unwrap_or("0").parse::<u16>();
+    match (major, minor) {
+        (Ok(major), Ok(minor)) => Ok((major, minor)),
+        _ => Err(format!("Version {:?} is not of the form major.minor.", version)),
+    }
+}
+
+/// Build the provenance to record when encoding.  The provenance of the
+/// file is recorded, along with any tags, source, and case given by the
+/// user.  Standard input has no file, so only the user's values are
+/// recorded, and if there are none no provenance is recorded.
+fn provenance(matches: &ArgMatches, file: Option<&Path>) -> Option<Provenance> {
+    let mut provenance = match file {
+        None => Provenance::new(),
+        Some(path) => Provenance::for_file(path),
+    };
+    if let Some(tags) = matches.values_of("tag") {
+        provenance.tags.extend(tags.map(|tag| tag.to_string()));
+    }
+    provenance.source = matches.value_of("source").map(|source| source.to_string());
+    provenance.case = matches.value_of("case").map(|case| case.to_string());
+    if provenance == Provenance::new() {
+        None
+    } else {
+        Some(provenance)
+    }
+}
+
+/// Build the options for encoding with the given key, version of the
+/// encoding, extra hashes, and provenance, if any.
+fn encode_options(key: Vec<u8>, version: (u16, u16), hashes: &[HashAlgorithm],
+    provenance: Option<Provenance>) -> PicoBuilder {
+    let mut options = PicoBuilder::new();
+    options.key(key).version(version.0, version.1).hashes(hashes);
+    if let Some(ref provenance) = provenance {
+        options.provenance(provenance);
+    }
+    options
+}
 
 /// Entry point when run from the command line.
 fn main() {
@@ -58,6 +169,9 @@ fn main() {
         .arg(Arg::with_name("decode")
             .conflicts_with("encode")
             .conflicts_with("header")
+            .conflicts_with("verify")
+            .conflicts_with("resize")
+            .conflicts_with("rekey")
             .short("d")
             .long("decode")
             .help("Decode files.")
@@ -65,6 +179,9 @@ fn main() {
         .arg(Arg::with_name("encode")
             .conflicts_with("decode")
             .conflicts_with("header")
+            .conflicts_with("verify")
+            .conflicts_with("resize")
+            .conflicts_with("rekey")
             .short("e")
             .long("encode")
             .help("Encode files.")
@@ -76,23 +193,109 @@ fn main() {
         .arg(Arg::with_name("header")
             .conflicts_with("encode")
             .conflicts_with("decode")
+            .conflicts_with("verify")
+            .conflicts_with("resize")
+            .conflicts_with("rekey")
             .possible_values(&["DICT", "JSON", "YAML", "XML"])
             .short("H")
             .long("header")
             .value_name("format")
             .help("Dump header information.")
             .takes_value(true))
+        .arg(Arg::with_name("verify")
+            .conflicts_with("encode")
+            .conflicts_with("decode")
+            .conflicts_with("header")
+            .conflicts_with("resize")
+            .conflicts_with("rekey")
+            .long("verify")
+            .help("Verify the hash of files.")
+            .takes_value(false))
+        .arg(Arg::with_name("resize")
+            .conflicts_with("encode")
+            .conflicts_with("decode")
+            .conflicts_with("header")
+            .conflicts_with("verify")
+            .conflicts_with("rekey")
+            .long("resize-metadata")
+            .value_name("bytes")
+            .help("Resize the metadata reserved in files.")
+            .takes_value(true))
+        .arg(Arg::with_name("rekey")
+            .conflicts_with("encode")
+            .conflicts_with("decode")
+            .conflicts_with("header")
+            .conflicts_with("verify")
+            .conflicts_with("resize")
+            .long("rekey")
+            .help("Change the key of files to the key given by --key.")
+            .takes_value(false))
         .arg(Arg::with_name("suffix")
             .short("s")
             .long("suffix")
             .default_value("")
             .help("Suffix to add to output files.")
             .takes_value(true))
+        .arg(Arg::with_name("threads")
+            .short("j")
+            .long("threads")
+            .value_name("count")
+            .help("Encode or decode files using multiple threads (0 for one per core).  \
+                Hashing while encoding still uses a single thread.")
+            .takes_value(true))
         .arg(Arg::with_name("key")
             .short("k")
             .long("key")
-            .help("Specify key for encoding.")
+            .help("Specify key for encoding or rekeying.")
+            .takes_value(true))
+        .arg(Arg::with_name("format-version")
+            .long("format-version")
+            .value_name("version")
+            .possible_values(&["1.0", "2.0"])
+            .help("Set the version of the encoding to write (default 2.0).")
+            .takes_value(true))
+        .arg(Arg::with_name("hash")
+            .long("hash")
+            .value_name("algorithm")
+            .possible_values(&["sha1", "sha256"])
+            .help("Also store the given hash of encoded files.")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("tag")
+            .long("tag")
+            .value_name("tag")
+            .help("Add a tag to the provenance of encoded files.")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("source")
+            .long("source")
+            .value_name("url")
+            .help("Record the source URL in the provenance of encoded files.")
+            .takes_value(true))
+        .arg(Arg::with_name("case")
+            .long("case")
+            .value_name("id")
+            .help("Record the case ID in the provenance of encoded files.")
             .takes_value(true))
+        .arg(Arg::with_name("restore-name")
+            .long("restore-name")
+            .help("Name decoded files with their original file names.")
+            .takes_value(false))
+        .arg(Arg::with_name("restore-mtime")
+            .long("restore-mtime")
+            .help("Restore the modification times of decoded files.")
+            .takes_value(false))
+        .arg(Arg::with_name("restore-mode")
+            .long("restore-mode")
+            .help("Restore the permissions of decoded files, except the executable bits.")
+            .takes_value(false))
+        .arg(Arg::with_name("restore-exec")
+            .long("restore-exec")
+            .requires("restore-mode")
+            .help("Also restore the executable bits of decoded files.")
+            .takes_value(false))
         .arg(Arg::with_name("files")
             .help("File names to process.")
             .multiple(true)
@@ -104,11 +307,22 @@ fn main() {
     // the files are required.
     let filelist = app_matches.values_of("files").unwrap();
     enum Operation {
-        Header, Encode, Decode,
-    };
+        Header, Encode, Decode, Verify, Resize(u32), Rekey,
+    }
     let mut op = Operation::Encode;
     if app_matches.is_present("header") { op = Operation::Header; }
     if app_matches.is_present("decode") { op = Operation::Decode; }
+    if app_matches.is_present("verify") { op = Operation::Verify; }
+    if app_matches.is_present("rekey") { op = Operation::Rekey; }
+    if let Some(size) = app_matches.value_of("resize") {
+        match size.parse::<u32>() {
+            Ok(value) => op = Operation::Resize(value),
+            Err(_) => {
+                eprintln!("ERROR: Metadata size must be a non-negative integer.");
+                exit(1);
+            }
+        }
+    }
     let header_format = match app_matches.value_of("header") {
         None => HeaderFormat::DICT,
         // This unwrap should not fail, since the format names are checked
@@ -126,82 +340,179 @@ fn main() {
     };
     // This unwrap should never fail since suffix has a default value.
     let suffix = app_matches.value_of("suffix").unwrap();
+    let restore = file::Restore {
+        filename: app_matches.is_present("restore-name"),
+        modified: app_matches.is_present("restore-mtime"),
+        permissions: app_matches.is_present("restore-mode"),
+        executable: app_matches.is_present("restore-exec"),
+    };
+    let version = match app_matches.value_of("format-version") {
+        None => (major(), minor()),
+        // This unwrap should not fail, since the versions are checked when
+        // parsing the command line.
+        Some(version) => parse_version(version).unwrap(),
+    };
+    let hashes: Vec<HashAlgorithm> = match app_matches.values_of("hash") {
+        None => Vec::new(),
+        // The names are checked when parsing the command line.
+        Some(names) => names.map(|name| match name {
+            "sha1" => HashAlgorithm::SHA1,
+            _ => HashAlgorithm::SHA256,
+        }).collect(),
+    };
+    let threads = match app_matches.value_of("threads") {
+        None => None,
+        Some(count) => match count.parse::<usize>() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                eprintln!("ERROR: Thread count must be a non-negative integer.");
+                exit(1);
+            }
+        },
+    };
+    if let Some(mut tags) = app_matches.values_of("tag") {
+        // Tags are stored one per line.
+        if tags.any(|tag| tag.contains(['\n', '\r'])) {
+            eprintln!("ERROR: Tags cannot contain line breaks.");
+            exit(1);
+        }
+    }
 
-    // Perform the operation for each specified file.
+    // Perform the operation for each specified file.  Keep going if a
+    // file fails, but remember the failure for the exit status.
+    let mut failed = false;
     for file in filelist {
+        // A single dash denotes standard input, and the result is written
+        // to standard output.
+        if file == "-" {
+            let result = match op {
+                Operation::Header | Operation::Verify | Operation::Resize(_) |
+                Operation::Rekey => {
+                    eprintln!("ERROR: Standard input can only be encoded or decoded.");
+                    failed = true;
+                    continue;
+                },
+                Operation::Encode => {
+                    let key = match parse_key(app_matches.value_of("key")) {
+                        Ok(value) => value,
+                        Err(err) => {
+                            eprintln!("ERROR: {}", err);
+                            exit(1);
+                        }
+                    };
+                    let options = encode_options(key, version, &hashes,
+                        provenance(&app_matches, None));
+                    file::encode_pipe_with(&mut stdin(), &mut stdout(), &options)
+                },
+                Operation::Decode => file::decode_pipe(&mut stdin(), &mut stdout()),
+            };
+            if let Err(err) = result {
+                eprintln!("ERROR: {}", err);
+                failed = true;
+            }
+            continue;
+        }
+
         // Check the file.
         let filepath = Path::new(&file);
         if filepath.is_dir() {
             eprintln!("ERROR: Argument {:?} is a folder.", file);
-            return;
+            failed = true;
+            continue;
         }
         if !filepath.exists() {
             eprintln!("ERROR: Argument {:?} is not found.", file);
-            return;
+            failed = true;
+            continue;
         }
         let basename = match filepath.file_stem() {
             None => {
                 eprintln!("ERROR: Argument {:?} is not a file.", file);
-                return;
+                failed = true;
+                continue;
             },
             Some(value) => value,
         }.to_string_lossy().into_owned();
         let oldname: String = filepath.to_string_lossy().into_owned();
 
         // Perform the correct operation.
-        match op {
+        let result = match op {
             Operation::Header => {
                 println!("Pico Header as {:?} for: {:?}", header_format, filepath);
-                match file::dump_header(&oldname, stdout(), &header_format) {
-                    Ok(()) => (),
-                    Err(err) => eprintln!("ERROR: {}", err),
-                };
+                file::dump_header(&oldname, stdout(), &header_format)
             },
 
             Operation::Encode => {
                 // See if the user specified a key; if not, generate one.
-                let key = match app_matches.value_of("key") {
-                    None => pico::gen_random_key(16),
-                    Some(hex) => {
-                        let hex = hex.to_uppercase().into_bytes();
-                        let hexlen = hex.len();
-                        if hexlen % 2 != 0 {
-                            // I think this is more helpful than the default given
-                            // by the hex package.
-                            eprintln!("ERROR: Key must be an even number of hex digits.");
-                            return;
-                        }
-                        if hexlen == 0 {
-                            // The hex package permits an empth string, so we have
-                            // to trap this here.
-                            eprintln!("ERROR: Key cannot be empty.");
-                            return;
-                        }
-                        match Vec::<u8>::from_hex(hex) {
-                            Ok(value) => value,
-                            Err(err) => {
-                                eprintln!("ERROR: {}", err);
-                                return;
-                            }
-                        }
+                let key = match parse_key(app_matches.value_of("key")) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        eprintln!("ERROR: {}", err);
+                        exit(1);
                     }
                 };
                 let newname = basename + suffix + extension;
-                println!("Encoding {:?} -> {:?}", oldname, newname);
-                match file::encode(&oldname, &newname, key, vec![], 0) {
-                    Ok(()) => (),
-                    Err(err) => eprintln!("ERROR: {}", err),
-                };
+                eprintln!("Encoding {:?} -> {:?}", oldname, newname);
+                let provenance = provenance(&app_matches, Some(filepath));
+                let options = encode_options(key, version, &hashes, provenance);
+                match threads {
+                    None => file::encode_with(&oldname, &newname, &options),
+                    Some(count) => file::encode_parallel_with(&oldname, &newname, &options,
+                        count),
+                }
             },
 
             Operation::Decode => {
-                let newname = basename + suffix + extension;
-                println!("Decoding {:?} -> {:?}", oldname, newname);
-                match file::decode(&oldname, &newname) {
-                    Ok(()) => (),
-                    Err(err) => eprintln!("ERROR: {}", err),
+                // The original file name, if restored, replaces the name
+                // derived from the encoded file.
+                let provenance = match file::read_provenance(&oldname) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        eprintln!("ERROR: {}", err);
+                        failed = true;
+                        continue;
+                    }
                 };
+                let newname = restore.path(provenance.as_ref(), basename + suffix + extension);
+                eprintln!("Decoding {:?} -> {:?}", oldname, newname);
+                match threads {
+                    None => file::decode(&oldname, &newname),
+                    Some(count) => file::decode_parallel(&oldname, &newname, count),
+                }.and_then(|_| restore.apply(provenance.as_ref(), &newname))
             },
+
+            Operation::Verify => {
+                let result = file::verify(&oldname);
+                match result {
+                    Ok(()) => println!("PASS {:?}", oldname),
+                    Err(_) => println!("FAIL {:?}", oldname),
+                };
+                result
+            },
+
+            Operation::Resize(size) => {
+                eprintln!("Resizing metadata of {:?} to {} bytes", oldname, size);
+                file::resize_metadata(&oldname, size)
+            },
+
+            Operation::Rekey => {
+                let key = match parse_key(app_matches.value_of("key")) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        eprintln!("ERROR: {}", err);
+                        exit(1);
+                    }
+                };
+                eprintln!("Rekeying {:?}", oldname);
+                file::rekey(&oldname, key)
+            },
+        };
+        if let Err(err) = result {
+            eprintln!("ERROR: {}", err);
+            failed = true;
         }
     }
+    if failed {
+        exit(1);
+    }
 }
@@ -0,0 +1,93 @@
+//! In-memory Pico encoding and decoding.
+//!
+//! These functions are convenient for tests and for services that already
+//! hold the data in memory, since no files are needed.
+//!
+//! ```
+//! let encoded = pico::encode_bytes(b"Martindale", vec![0x55, 0x21], b"Newcomb").unwrap();
+//! let (data, metadata, header) = pico::decode_bytes(&encoded).unwrap();
+//! assert_eq!(data, b"Martindale");
+//! assert_eq!(metadata, b"Newcomb");
+//! assert_eq!(header.key, vec![0x55, 0x21]);
+//! ```
+
+use std::io::Cursor;
+use pico::Pico;
+use header::PicoHeader;
+use errors::Result;
+
+/// Encode data in memory, returning the complete Pico-encoded file.
+///
+/// # Arguments
+/// * `data`     - The data to encode.
+/// * `key`      - The encryption key to use.
+/// * `metadata` - The metadata to store.  Exactly enough space is reserved
+///   to hold it.
+pub fn encode_bytes(data: &[u8], key: Vec<u8>, metadata: &[u8]) -> Result<Vec<u8>> {
+    // Write the header and metadata.  The hashes are computed directly
+    // from the data, so the header is complete.
+    let mut encoded = Cursor::new(Vec::new());
+    let (offset, cipher) = {
+        let mut pico = Pico::new(&mut encoded, key, metadata.len() as u32)?;
+        pico.put_metadata(0, metadata)?;
+        let mut hashes = pico.new_hashes();
+        hashes.consume(data);
+        pico.set_hash(&hashes)?;
+        pico.flush()?;
+        let header = pico.get_header();
+        (pico.get_offset() as usize, header.get_cipher()?.cipher(&header.key))
+    };
+
+    // Copy the data once, straight into the output, and encrypt it there.
+    let mut encoded = encoded.into_inner();
+    encoded.resize(offset, 0);
+    encoded.reserve_exact(data.len());
+    encoded.extend_from_slice(data);
+    cipher.apply(0, &mut encoded[offset..]);
+    Ok(encoded)
+}
+
+/// Decode a complete Pico-encoded file held in memory.  The decoded data,
+/// the entire metadata section, and the header are returned.
+pub fn decode_bytes(bytes: &[u8]) -> Result<(Vec<u8>, Vec<u8>, PicoHeader)> {
+    let mut pico = Pico::open(Cursor::new(bytes))?;
+
+    // Read the metadata.
+    let mut metadata = vec![0u8; pico.get_md_length() as usize];
+    let count = pico.get_metadata(0, &mut metadata)?;
+    metadata.truncate(count);
+
+    // Read the data.
+    let mut data = vec![0u8; pico.data_len()? as usize];
+    let count = pico.get(0, &mut data)?;
+    data.truncate(count);
+    Ok((data, metadata, pico.get_header().clone()))
+}
+
+#[allow(unused_imports)]
+mod test {
+    use md5;
+    use super::{encode_bytes, decode_bytes};
+
+    #[test]
+    fn memory_test_1() {
+        let text = b"Martindale".repeat(1000);
+        let encoded = encode_bytes(&text, vec![0x55, 0x21, 0xe4, 0x9a], b"Newcomb").unwrap();
+        let (data, metadata, header) = decode_bytes(&encoded).unwrap();
+        assert_eq!(data, text);
+        assert_eq!(metadata, b"Newcomb");
+        assert_eq!(header.key, vec![0x55, 0x21, 0xe4, 0x9a]);
+        assert_eq!(header.md_length, 7);
+        assert_eq!(header.hash.to_vec(), md5::compute(&text).to_vec());
+    }
+
+    #[test]
+    fn memory_test_2() {
+        let encoded = encode_bytes(b"", vec![0x55], b"").unwrap();
+        let (data, metadata, header) = decode_bytes(&encoded).unwrap();
+        assert!(data.is_empty());
+        assert!(metadata.is_empty());
+        assert_eq!(header.hash.to_vec(), md5::compute(b"").to_vec());
+        assert!(decode_bytes(&encoded[..10]).is_err());
+    }
+}
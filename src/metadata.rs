@@ -0,0 +1,317 @@
+//! Structured metadata stored in the metadata section.
+//!
+//! The metadata section of a Pico file is a block of raw bytes.  This
+//! module defines a map from string keys to typed values, and a binary
+//! encoding of the map that is stored at the start of the metadata section.
+//!
+//! # Encoding
+//!
+//! ^ Data  ^ Meaning ^
+//! | 4 bytes | Magic, `PMD` followed by the encoding version, 1 |
+//! | 4 bytes | Number of bytes in the entries that follow |
+//! | 2 bytes | Length of the key of an entry |
+//! | key | The key, as UTF-8 |
+//! | 1 byte | Type of the value of the entry |
+//! | 4 bytes | Length of the value of the entry |
+//! | value | The value |
+//!
+//! All integers are big-endian.  Entries are sorted by key, and repeat to
+//! fill the given number of bytes.  Any bytes after the entries are
+//! ignored, so the encoding can be rewritten in place.  Values are typed as
+//! follows.
+//!
+//! ^ Type ^ Value ^
+//! | 1 | Text, as UTF-8 |
+//! | 2 | Signed 64-bit integer |
+//! | 3 | Raw bytes |
+//! | 4 | Timestamp, as signed 64-bit seconds and 32-bit nanoseconds since the Unix epoch |
+//!
+//! Metadata sections that do not start with the magic hold legacy raw
+//! metadata.  These are read as a map with the single key `LEGACY_KEY`,
+//! whose value is the raw bytes without trailing zeros.
+//!
+//! ```
+//! use pico::{Metadata, MetadataValue};
+//!
+//! let mut metadata = Metadata::new();
+//! metadata.insert("submitter", MetadataValue::Text("Martindale".to_string()));
+//! metadata.insert("size", MetadataValue::Int(1024));
+//! let bytes = metadata.encode().unwrap();
+//! assert_eq!(Metadata::decode(&bytes).unwrap(), metadata);
+//! ```
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use intbytes::ByteDump;
+use errors::{PicoError, Result};
+
+/// Magic bytes at the start of encoded metadata.
+pub const METADATA_MAGIC: [u8; 4] = [0x50, 0x4d, 0x44, 0x01];
+
+/// Key used for legacy raw metadata.
+pub const LEGACY_KEY: &str = "legacy";
+
+/// Size (in bytes) of the metadata magic and entries length.
+const PREFIX_LEN: usize = 8;
+
+/// Type tags of the values.
+const TEXT_TYPE: u8 = 1;
+const INT_TYPE: u8 = 2;
+const BYTES_TYPE: u8 = 3;
+const TIMESTAMP_TYPE: u8 = 4;
+
+/// A typed metadata value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataValue {
+    /// Text.
+    Text(String),
+    /// A signed integer.
+    Int(i64),
+    /// Raw bytes.
+    Bytes(Vec<u8>),
+    /// A point in time, with nanosecond precision.
+    Timestamp(SystemTime),
+}
+
+impl MetadataValue {
+    /// Get the type tag and the encoded value.
+    fn encode(&self) -> (u8, Vec<u8>) {
+        match *self {
+            MetadataValue::Text(ref text) => (TEXT_TYPE, text.as_bytes().to_vec()),
+            MetadataValue::Int(value) => (INT_TYPE, (value as u64).get_bytes().to_vec()),
+            MetadataValue::Bytes(ref bytes) => (BYTES_TYPE, bytes.clone()),
+            MetadataValue::Timestamp(time) => {
+                // Split the time into whole seconds, which may be negative,
+                // and a non-negative number of nanoseconds.
+                let (seconds, nanos) = match time.duration_since(UNIX_EPOCH) {
+                    Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+                    Err(err) => {
+                        let before = err.duration();
+                        let mut seconds = -(before.as_secs() as i64);
+                        let mut nanos = before.subsec_nanos();
+                        if nanos > 0 {
+                            seconds -= 1;
+                            nanos = 1_000_000_000 - nanos;
+                        }
+                        (seconds, nanos)
+                    }
+                };
+                let mut bytes = (seconds as u64).get_bytes().to_vec();
+                bytes.extend_from_slice(&nanos.get_bytes());
+                (TIMESTAMP_TYPE, bytes)
+            }
+        }
+    }
+
+    /// Decode a value of the given type.  `None` is returned if the value
+    /// is malformed or the type is not known.
+    fn decode(kind: u8, bytes: &[u8]) -> Option<MetadataValue> {
+        match kind {
+            TEXT_TYPE => String::from_utf8(bytes.to_vec()).ok().map(MetadataValue::Text),
+            INT_TYPE => {
+                if bytes.len() != 8 { return None; }
+                Some(MetadataValue::Int(be_u64(bytes) as i64))
+            }
+            BYTES_TYPE => Some(MetadataValue::Bytes(bytes.to_vec())),
+            TIMESTAMP_TYPE => {
+                if bytes.len() != 12 { return None; }
+                let seconds = be_u64(&bytes[0..8]) as i64;
+                let nanos = be_u64(&bytes[8..12]) as u32;
+                if nanos >= 1_000_000_000 { return None; }
+                let time = if seconds >= 0 {
+                    UNIX_EPOCH.checked_add(Duration::new(seconds as u64, nanos))
+                } else {
+                    UNIX_EPOCH.checked_sub(Duration::new(seconds.unsigned_abs(), 0))
+                        .and_then(|time| time.checked_add(Duration::new(0, nanos)))
+                };
+                time.map(MetadataValue::Timestamp)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Assemble a big-endian unsigned integer from up to eight bytes.
+fn be_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, byte| (value << 8) | (*byte as u64))
+}
+
+/// Check that a length can be stored in four bytes.
+fn check_length(length: usize) -> Result<u32> {
+    if length > u32::MAX as usize {
+        return Err(PicoError::MetadataTooLarge(u32::MAX, u32::MAX));
+    }
+    Ok(length as u32)
+}
+
+/// A map from string keys to typed metadata values.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Metadata {
+    /// The entries, sorted by key.
+    entries: BTreeMap<String, MetadataValue>,
+}
+
+impl Metadata {
+    /// Create an empty map.
+    pub fn new() -> Metadata {
+        Metadata { entries: BTreeMap::new() }
+    }
+
+    /// Set the value for a key, returning the previous value, if any.
+    pub fn insert<K: Into<String>>(&mut self, key: K, value: MetadataValue)
+        -> Option<MetadataValue> {
+        self.entries.insert(key.into(), value)
+    }
+
+    /// Get the value for a key.
+    pub fn get(&self, key: &str) -> Option<&MetadataValue> {
+        self.entries.get(key)
+    }
+
+    /// Remove a key, returning its value, if any.
+    pub fn remove(&mut self, key: &str) -> Option<MetadataValue> {
+        self.entries.remove(key)
+    }
+
+    /// Get the number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Determine whether there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over the entries, in key order.
+    pub fn iter(&self) -> btree_map::Iter<'_, String, MetadataValue> {
+        self.entries.iter()
+    }
+
+    /// Encode the map.  If a key is longer than 65535 bytes, then
+    /// `PicoError::BadMetadata` is returned with the offset of its entry.
+    /// If a value or the entire encoding is 4 GiB or longer, then
+    /// `PicoError::MetadataTooLarge` is returned.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut entries = Vec::new();
+        for (key, value) in &self.entries {
+            let offset = PREFIX_LEN + entries.len();
+            if key.len() > u16::MAX as usize {
+                return Err(PicoError::BadMetadata(offset.min(u32::MAX as usize) as u32));
+            }
+            let (kind, bytes) = value.encode();
+            entries.extend_from_slice(&(key.len() as u16).get_bytes());
+            entries.extend_from_slice(key.as_bytes());
+            entries.push(kind);
+            entries.extend_from_slice(&check_length(bytes.len())?.get_bytes());
+            entries.extend_from_slice(&bytes);
+        }
+        let mut encoded = METADATA_MAGIC.to_vec();
+        encoded.extend_from_slice(&check_length(entries.len())?.get_bytes());
+        encoded.extend_from_slice(&entries);
+        Ok(encoded)
+    }
+
+    /// Determine whether the bytes start with encoded metadata, rather than
+    /// legacy raw metadata.
+    pub fn is_encoded(bytes: &[u8]) -> bool {
+        bytes.starts_with(&METADATA_MAGIC)
+    }
+
+    /// Decode a map from the start of the given bytes.  Bytes after the
+    /// encoded map are ignored.  If the bytes do not start with encoded
+    /// metadata, they are treated as legacy raw metadata.  If they are
+    /// empty or all zero, the map is empty.
+    pub fn decode(bytes: &[u8]) -> Result<Metadata> {
+        let mut metadata = Metadata::new();
+        if !Metadata::is_encoded(bytes) {
+            let end = bytes.iter().rposition(|byte| *byte != 0).map_or(0, |index| index + 1);
+            if end > 0 {
+                metadata.insert(LEGACY_KEY, MetadataValue::Bytes(bytes[0..end].to_vec()));
+            }
+            return Ok(metadata);
+        }
+
+        // Find the entries.
+        if bytes.len() < PREFIX_LEN {
+            return Err(PicoError::BadMetadata(bytes.len() as u32));
+        }
+        let length = be_u64(&bytes[4..PREFIX_LEN]) as usize;
+        if bytes.len() - PREFIX_LEN < length {
+            return Err(PicoError::BadMetadata(PREFIX_LEN as u32));
+        }
+        let entries = &bytes[PREFIX_LEN..PREFIX_LEN + length];
+
+        // Decode each entry.
+        let mut position = 0;
+        while position < entries.len() {
+            let bad = PicoError::BadMetadata((PREFIX_LEN + position) as u32);
+            let rest = &entries[position..];
+            if rest.len() < 2 { return Err(bad); }
+            let key_len = be_u64(&rest[0..2]) as usize;
+            let value_start = 2 + key_len + 5;
+            if rest.len() < value_start { return Err(bad); }
+            let key = match String::from_utf8(rest[2..2 + key_len].to_vec()) {
+                Ok(key) => key,
+                Err(_) => return Err(bad),
+            };
+            let kind = rest[2 + key_len];
+            let value_len = be_u64(&rest[3 + key_len..value_start]) as usize;
+            if rest.len() - value_start < value_len { return Err(bad); }
+            let value = match MetadataValue::decode(kind, &rest[value_start..value_start + value_len]) {
+                Some(value) => value,
+                None => return Err(bad),
+            };
+            metadata.insert(key, value);
+            position += value_start + value_len;
+        }
+        Ok(metadata)
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+    use errors::PicoError;
+    use super::{Metadata, MetadataValue, LEGACY_KEY};
+
+    #[test]
+    fn metadata_test_1() {
+        let mut metadata = Metadata::new();
+        metadata.insert("name", MetadataValue::Text("Martindale".to_string()));
+        metadata.insert("count", MetadataValue::Int(-3));
+        metadata.insert("raw", MetadataValue::Bytes(vec![0, 1, 2]));
+        metadata.insert("after", MetadataValue::Timestamp(UNIX_EPOCH + Duration::new(1500000000, 25)));
+        metadata.insert("before", MetadataValue::Timestamp(UNIX_EPOCH - Duration::new(10, 25)));
+        let mut bytes = metadata.encode().unwrap();
+        assert_eq!(&bytes[0..8], &[0x50, 0x4d, 0x44, 0x01, 0x00, 0x00, 0x00, 0x67]);
+        bytes.extend_from_slice(&[0u8; 20]);
+        assert_eq!(Metadata::decode(&bytes).unwrap(), metadata);
+
+        // Damage the length of the first value.
+        bytes[16] = 0x7f;
+        match Metadata::decode(&bytes) {
+            Err(PicoError::BadMetadata(8)) => (),
+            _ => panic!("Expected bad metadata."),
+        }
+    }
+
+    #[test]
+    fn metadata_test_2() {
+        assert!(Metadata::decode(&[]).unwrap().is_empty());
+        assert!(Metadata::decode(&[0u8; 10]).unwrap().is_empty());
+        let metadata = Metadata::decode(b"Newcomb\0\0\0").unwrap();
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata.get(LEGACY_KEY), Some(&MetadataValue::Bytes(b"Newcomb".to_vec())));
+
+        // Keys must fit in two bytes.
+        let mut metadata = Metadata::new();
+        metadata.insert("count", MetadataValue::Int(7));
+        metadata.insert("k".repeat(65536), MetadataValue::Int(7));
+        match metadata.encode() {
+            Err(PicoError::BadMetadata(28)) => (),
+            _ => panic!("Expected bad metadata."),
+        }
+    }
+}
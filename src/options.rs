@@ -0,0 +1,166 @@
+//! Limits applied when opening Pico-encoded files.
+//!
+//! Pico-encoded files often come from hostile sources, so the header is
+//! never trusted.  Every length in the header is checked against a limit
+//! before anything is allocated, and the file must be long enough to hold
+//! the header and metadata it describes.  The defaults accept any file
+//! this library can write; tighten them when opening untrusted files.
+//!
+//! ```
+//! use std::io::Cursor;
+//! use pico::{PicoOpenOptions, errors::PicoError};
+//!
+//! let encoded = pico::encode_bytes(b"Martindale", vec![0x55, 0x21], b"Newcomb").unwrap();
+//! let pico = PicoOpenOptions::new()
+//!     .max_key_length(64)
+//!     .max_metadata_length(4096)
+//!     .open(Cursor::new(&encoded))
+//!     .unwrap();
+//! assert_eq!(pico.get_md_length(), 7);
+//! match PicoOpenOptions::new().max_metadata_length(4).open(Cursor::new(&encoded)) {
+//!     Err(PicoError::LimitExceeded("metadata", 7, 4)) => (),
+//!     _ => panic!("Expected the metadata limit to be exceeded."),
+//! };
+//! ```
+
+use std::fs::File;
+use std::io::{Read, Seek};
+use errors::Result;
+use header::PicoHeader;
+use pico::Pico;
+use shared::SharedPicoReader;
+
+/// Default limit on the length of the extension area.
+const DEFAULT_MAX_EXTENSIONS_LENGTH: u32 = 1 << 20;
+
+/// Options for opening an existing Pico-encoded file.  Set the limits, and
+/// then call `open` or `open_shared`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PicoOpenOptions {
+    /// The longest key allowed, in bytes.
+    pub(crate) max_key_length: u16,
+    /// The longest extension area allowed, in bytes.
+    pub(crate) max_extensions_length: u32,
+    /// The most metadata allowed, in bytes.
+    pub(crate) max_metadata_length: u32,
+}
+
+impl Default for PicoOpenOptions {
+    fn default() -> PicoOpenOptions {
+        PicoOpenOptions::new()
+    }
+}
+
+impl PicoOpenOptions {
+    /// Start with the default limits.  Keys and metadata of any length are
+    /// allowed, and extension areas of up to 1 MiB.
+    pub fn new() -> PicoOpenOptions {
+        PicoOpenOptions {
+            max_key_length: u16::MAX,
+            max_extensions_length: DEFAULT_MAX_EXTENSIONS_LENGTH,
+            max_metadata_length: u32::MAX,
+        }
+    }
+
+    /// Set the longest key allowed, in bytes.
+    pub fn max_key_length(&mut self, length: u16) -> &mut PicoOpenOptions {
+        self.max_key_length = length;
+        self
+    }
+
+    /// Set the longest extension area allowed, in bytes.
+    pub fn max_extensions_length(&mut self, length: u32) -> &mut PicoOpenOptions {
+        self.max_extensions_length = length;
+        self
+    }
+
+    /// Set the most metadata allowed, in bytes.
+    pub fn max_metadata_length(&mut self, length: u32) -> &mut PicoOpenOptions {
+        self.max_metadata_length = length;
+        self
+    }
+
+    /// Read a header from a stream, applying these limits.  The stream must
+    /// be positioned at the start of the header.
+    pub fn read_header<R: Read>(&self, source: &mut R) -> Result<PicoHeader> {
+        PicoHeader::read_limited(source, self)
+    }
+
+    /// Open an existing Pico-encoded file, applying these limits.
+    pub fn open<T: Seek + Read>(&self, file: T) -> Result<Pico<T>> {
+        Pico::open_with(file, self)
+    }
+
+    /// Open an existing Pico-encoded file for reading from several threads,
+    /// applying these limits.
+    pub fn open_shared(&self, file: File) -> Result<SharedPicoReader> {
+        SharedPicoReader::open_with(file, self)
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use std::io::Cursor;
+    use builder::PicoBuilder;
+    use errors::PicoError;
+    use header::{HashAlgorithm, PicoHeader};
+    use metadata::Metadata;
+    use super::PicoOpenOptions;
+
+    #[test]
+    fn options_test_1() {
+        let encoded = ::encode_bytes(b"Martindale", vec![0x55, 0x21, 0xe4], b"Newcomb").unwrap();
+        match PicoOpenOptions::new().max_key_length(2).open(Cursor::new(&encoded)) {
+            Err(PicoError::LimitExceeded("key", 3, 2)) => (),
+            _ => panic!("Expected the key limit to be exceeded."),
+        }
+        let mut hashed = Cursor::new(Vec::new());
+        PicoBuilder::new().key(vec![0x55, 0x21, 0xe4]).hashes(&[HashAlgorithm::SHA256])
+            .create(&mut hashed).unwrap().close().unwrap();
+        match PicoOpenOptions::new().max_extensions_length(8).open(hashed) {
+            Err(PicoError::LimitExceeded("extension area", 38, 8)) => (),
+            _ => panic!("Expected the extension area limit to be exceeded."),
+        }
+        PicoOpenOptions::new().max_metadata_length(7).open(Cursor::new(&encoded)).unwrap();
+
+        // The file must reach the start of the data.
+        let header = PicoHeader::parse(&encoded).unwrap();
+        let short = &encoded[..header.offset as usize - 1];
+        match PicoOpenOptions::new().open(Cursor::new(short)) {
+            Err(PicoError::Truncated(length)) => assert_eq!(length, header.offset as u64),
+            _ => panic!("Expected a truncated file."),
+        }
+        PicoOpenOptions::new().open(Cursor::new(&encoded[..header.offset as usize])).unwrap();
+
+        // A huge extension area is rejected before it is allocated.
+        let mut huge = encoded.clone();
+        huge[31..35].copy_from_slice(&[0xff, 0xff, 0xff, 0xf0]);
+        match PicoHeader::parse(&huge) {
+            Err(PicoError::LimitExceeded("extension area", 0xfffffff0, _)) => (),
+            _ => panic!("Expected the extension area limit to be exceeded."),
+        }
+    }
+
+    #[test]
+    fn options_test_2() {
+        // Damage every byte of the header and metadata in turn, and cut the
+        // file short at every length.  Nothing may panic.
+        let mut metadata = Metadata::new();
+        metadata.insert("name", ::MetadataValue::Text("Martindale".to_string()));
+        let encoded = ::encode_bytes(b"Martindale", vec![0x55, 0x21, 0xe4], &metadata.encode().unwrap()).unwrap();
+        let try_open = |bytes: &[u8]| {
+            if let Ok(mut pico) = PicoOpenOptions::new().open(Cursor::new(bytes)) {
+                let _ = pico.read_metadata_map();
+                let _ = pico.verify();
+            }
+        };
+        for index in 0..encoded.len() {
+            for value in &[0x00, 0x01, 0x7f, 0x80, 0xff] {
+                let mut damaged = encoded.clone();
+                damaged[index] = *value;
+                try_open(&damaged);
+            }
+            try_open(&encoded[..index]);
+        }
+    }
+}
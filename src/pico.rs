@@ -11,7 +11,24 @@
 //! | 0x1A - 0x1B | Key Length High Byte | `get_key().len()` |
 //! | 0x1C - | Start of Key Bytes | `get_key()` |
 //!
-//! The end of the key is the start of the metadata, if any.
+//! Starting with version 2.0, the key is followed by an extension area.
+//!
+//! ^ Data  ^ Meaning ^
+//! | 4 bytes | Length of the extension entries that follow |
+//! | 2 bytes | Type of an extension entry |
+//! | 4 bytes | Length of the extension entry value |
+//! | value | Value of the extension entry |
+//!
+//! Each entry is a type, length, and value, and entries repeat to fill the
+//! extension area.  Entries of unknown type are ignored, but preserved.
+//! See `get_header().extensions`.
+//!
+//! Extensions hold the SHA-1 and SHA-256 hashes of the data, if they are
+//! stored, and the cipher used to encrypt the data, if it is not xor.
+//!
+//! The end of the header, which is the end of the key for version 1.0
+//! and the end of the extension area otherwise, is the start of the
+//! metadata, if any.
 //! The length of the metadata section is given by
 //! `get_md_length()`.
 //!
@@ -19,16 +36,29 @@
 //! immediately follows the metadata, or the key if there is
 //! no metadata.
 
+use std::io;
 use std::io::{Read, Write, Seek, SeekFrom};
-use header::HeaderFormat;
+use std::mem;
+use header::{HashAlgorithm, HeaderFormat, PicoHeader};
 use constants::*;
-use crypt::crypt;
-use intbytes::{ByteDump, dump_vec};
+use crypt::Cipher;
 use errors::{PicoError, Result};
-use md5;
+use hashes::Hashes;
+use setlen::SetLen;
+use metadata::Metadata;
+use builder::PicoBuilder;
+use options::PicoOpenOptions;
 use rand::Rng;
 use rand::os::OsRng;
 
+/// Message for the impossible case of using a file after `into_inner`.
+const NO_FILE: &str = "The file is only taken when the structure is consumed.";
+
+/// A function that flushes a Pico-encoded file.  This is kept so that the
+/// file can be flushed when dropped, even though `Drop` cannot require the
+/// file to be writable.
+type FlushFn<T> = fn(&mut Pico<T>) -> Result<()>;
+
 /// Generate a random string of bytes usable for an encryption
 /// key.  This uses the `rand` crate and especially the `OsRand`
 /// generator.  If the length is zero, then a key of length one
@@ -46,12 +76,37 @@ pub fn gen_random_key(length: u16) -> Vec<u8> {
     key
 }
 
+/// Read until the buffer is full or the end of the stream is reached, so
+/// that short reads, which pipes and network file systems may return, are
+/// not mistaken for the end of the stream.  The number of bytes read is
+/// returned.
+pub(crate) fn read_fully<R: Read>(source: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut count = 0;
+    while count < buffer.len() {
+        match source.read(&mut buffer[count..]) {
+            Ok(0) => break,
+            Ok(num) => count += num,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(count)
+}
+
 /// Wrapper to handle Pico encoding and decoding.
 ///
 /// # Use
-/// You may use this to create a new file via the `new` method, or you
-/// can work with an existing file via the `open` method.  In both cases
-/// you need to actually open the file, first.
+/// You may use this to create a new file via the `new` method, or via a
+/// `PicoBuilder` for more options, or you can work with an existing file
+/// via the `open` method.  In all cases you need to actually open the
+/// file, first.
+///
+/// ## Read-Only Access
+/// Opening, reading data and metadata, and dumping the header only require
+/// the file to support `Read` and `Seek`.  If you open a file without write
+/// access, only those methods are available, and the file is guaranteed
+/// never to be modified.  The methods that modify the file (`new`, `put`,
+/// `put_metadata`, and `flush`) require `Write` as well.
 ///
 /// ## Metadata
 /// You can access metadata via the `put_metadata` and `get_metadata`
@@ -59,51 +114,88 @@ pub fn gen_random_key(length: u16) -> Vec<u8> {
 /// Check `get_md_length` to find out the number of bytes reserved for
 /// metadata.
 ///
+/// Structured metadata, a map from string keys to typed values, can be
+/// stored in the same space via `write_metadata_map` and read back via
+/// `read_metadata_map`.
+///
 /// ## Data
 /// Data is read and decrypted via the `get_data` method, and data is
 /// encrypted and written via the `put_data` method.  Limits on data size
 /// are controlled by the underlying file system (and the `usize` type).
-pub struct Pico<T: Seek + Read + Write> {
-    /// Major version number in the file.
-    major: u16,
-    /// MinMinorTion number in the file.
-    minor: u16,
-    /// Zero-based offset to the start of data.
-    offset: u32,
-    /// The hash.
-    hash: [u8; HASH_LEN],
-    /// The key length.
-    key: Vec<u8>,
+pub struct Pico<T: Seek + Read> {
+    /// The header.
+    header: PicoHeader,
+    /// The cipher used to encrypt and decrypt data.
+    cipher: Box<dyn Cipher>,
     /// Whether the hash is valid.
     is_hash_valid: bool,
-    /// Zero-based start of metadata.
-    md_start: usize,
-    /// The metadata length in bytes.
-    md_length: usize,
-    /// The header owns the file.
-    file: T,
+    /// Running hashes of the data from the start, if writes have so far
+    /// been sequential.
+    hash_context: Option<Hashes>,
+    /// The number of data bytes consumed by the running hash.
+    hashed: usize,
+    /// The number of data bytes in the file, if known.  Keeping this saves
+    /// seeking to the end of the file on every write.
+    known_len: Option<u64>,
+    /// If there are changes that have not been flushed, the function that
+    /// flushes them.  This is called when the structure is dropped.
+    unflushed: Option<FlushFn<T>>,
+    /// The header owns the file.  This is only taken by `into_inner`.
+    file: Option<T>,
 }
 
-impl<T: Seek + Read + Write> Pico<T> {
+impl<T: Seek + Read> Drop for Pico<T> {
+    /// Flush any changes.  Errors cannot be returned from here, so they are
+    /// logged.  Call `close` to see them.
+    fn drop(&mut self) {
+        if self.file.is_none() {
+            return;
+        }
+        if let Some(flush) = self.unflushed.take() {
+            if let Err(err) = flush(self) {
+                error!("Unable to flush Pico-encoded file: {}", err);
+            }
+        }
+    }
+}
+
+impl<T: Seek + Read> Pico<T> {
     /// Get the version number of the encoding used to create this file.
     pub fn get_version(&self) -> (u16, u16) {
-        (self.major, self.minor)
+        (self.header.major, self.header.minor)
     }
 
     /// Get the zero-based offset within the file of the first data byte.
     pub fn get_offset(&self) -> u32 {
-        self.offset
+        self.header.offset
     }
 
     /// Get the hash value of the data in the file.  Note that this may
     /// cause the hash to be computed if it is not already valid.
     pub fn get_hash(&self) -> Vec<u8> {
-        self.hash.to_vec()
+        self.header.hash.to_vec()
+    }
+
+    /// Get the SHA-256 hash of the data in the file, if the file stores
+    /// one.  Note that this is only up to date after `flush`.
+    pub fn get_sha256(&self) -> Option<Vec<u8>> {
+        self.header.get_stored_hash(HashAlgorithm::SHA256).map(|hash| hash.to_vec())
+    }
+
+    /// Get the SHA-1 hash of the data in the file, if the file stores one.
+    /// Note that this is only up to date after `flush`.
+    pub fn get_sha1(&self) -> Option<Vec<u8>> {
+        self.header.get_stored_hash(HashAlgorithm::SHA1).map(|hash| hash.to_vec())
     }
 
     /// Get the encryption key used to encrypt the data in this file.
     pub fn get_key(&self) -> Vec<u8> {
-        self.key.clone()
+        self.header.key.clone()
+    }
+
+    /// Get the header of this file.
+    pub fn get_header(&self) -> &PicoHeader {
+        &self.header
     }
 
     /// Dump the content of the header in the correct form.
@@ -111,216 +203,75 @@ impl<T: Seek + Read + Write> Pico<T> {
     /// # Arguments
     /// * `target` - The writer to get the output.
     /// * `form`   - The format to use to write.
-    #[allow(unused_must_use)]
     pub fn dump_header<U>(&self, target: &mut U, form: &HeaderFormat)
     where
         U: Write,
     {
-        let (major, minor) = self.get_version();
-        let hash = self.get_hash();
-        let key = self.get_key();
-        match *form {
-            HeaderFormat::DICT => {
-                write!(target, "{{\n");
-                write!(target, "    \"magic\" : [ ");
-                ::magic().dump_bytes(target, true);
-                write!(target, " ],\n");
-                write!(target, "    \"major\" : {},\n", major);
-                write!(target, "    \"minor\" : {},\n", minor);
-                write!(target, "    \"offset\" : {},\n", self.get_offset());
-                write!(target, "    \"hash\" : [ ");
-                dump_vec(target, &hash, true, true);
-                write!(target, " ],\n");
-                write!(target, "    \"key_length\" : {},\n", key.len());
-                write!(target, "    \"key\" : [ ");
-                dump_vec(target, &key, true, true);
-                write!(target, " ],\n");
-                write!(target, "    \"md_length\" : {},\n", self.get_md_length());
-                write!(target, "}}\n");
-            }
-            HeaderFormat::JSON => {
-                write!(target, "{{\n");
-                write!(target, "    \"magic\" : [ ");
-                ::magic().dump_bytes(target, false);
-                write!(target, " ],\n");
-                write!(target, "    \"major\" : {},\n", major);
-                write!(target, "    \"minor\" : {},\n", minor);
-                write!(target, "    \"offset\" : {},\n", self.get_offset());
-                write!(target, "    \"hash\" : [ ");
-                dump_vec(target, &hash, false, true);
-                write!(target, " ],\n");
-                write!(target, "    \"key_length\" : {},\n", key.len());
-                write!(target, "    \"key\" : [ ");
-                dump_vec(target, &key, false, true);
-                write!(target, " ],\n");
-                write!(target, "    \"md_length\" : {},\n", self.get_md_length());
-                write!(target, "}}\n");
-            }
-            HeaderFormat::YAML => {
-                write!(target, "magic: [ ");
-                ::magic().dump_bytes(target, false);
-                write!(target, " ]\n");
-                write!(target, "major: {}\n", major);
-                write!(target, "minor: {}\n", minor);
-                write!(target, "offset: {}\n", self.get_offset());
-                write!(target, "hash: [ ");
-                dump_vec(target, &hash, false, true);
-                write!(target, " ]\n");
-                write!(target, "key_length: {}\n", key.len());
-                write!(target, "key: [ ");
-                dump_vec(target, &key, false, true);
-                write!(target, " ]\n");
-                write!(target, "md_length: {}\n", self.get_md_length());
-            }
-            HeaderFormat::XML => {
-                write!(
-                    target,
-                    r#"<pico magic='0x{:04X}' major='{}' minor='{}' offset='{}'"#,
-                    ::magic(),
-                    major,
-                    minor,
-                    self.get_offset()
-                );
-                write!(target, " hash='");
-                dump_vec(target, &hash, true, false);
-                write!(target, " key='");
-                dump_vec(target, &key, true, false);
-                write!(target, " md_length='{}' />", self.get_md_length());
-            }
-        }
+        self.header.dump(target, form);
     }
 
-    /// Create a new Pico-encoded file.
-    ///
-    /// # Arguments
-    /// * `file`      - An open file for writing that must support `seek`.
-    /// * `key`       - The encryption key to use.  If this is empty, a random key
-    ///                 is generated.
-    /// * `md_length` - The number of bytes to reserve for metadata.  Can be zero.
-    pub fn new(file: T, key: Vec<u8>, md_length: u32) -> Result<Pico<T>>
-    where
-        T: Read + Write + Seek,
-    {
-        let md_start = key.len() + KEY_POS;
-        let mut pico = Pico {
-            major: MAJOR,
-            minor: MINOR,
-            offset: md_start as u32 + md_length,
-            hash: [0; HASH_LEN as usize],
-            is_hash_valid: false,
-            key: key,
-            md_start: md_start,
-            md_length: md_length as usize,
-            file: file,
-        };
-        pico.write_header()?;
-        pico.file.flush().map_err(
-            |err| PicoError::WriteFailed(1001, err),
-        )?;
-        Ok(pico)
+    /// Initialize from an existing, open, Pico-encoded file, with the
+    /// default limits.  Use `PicoOpenOptions` to set the limits.
+    pub fn open(file: T) -> Result<Pico<T>> {
+        Pico::open_with(file, &PicoOpenOptions::new())
     }
 
-    /// Initialize from an existing, open, Pico-encoded file.
-    pub fn open(mut file: T) -> Result<Pico<T>>
-    where
-        T: Read + Write + Seek,
-    {
-        // Allocate some buffers.
-        let mut u16buf = [0u8; 2];
-        let mut u32buf = [0u8; 4];
-
-        // Little functions to assemble types from the buffers.
-        fn tou16(buf: [u8; 2]) -> u16 {
-            ((buf[0] as u16) << 8) | (buf[1] as u16)
-        }
-        fn tou32(buf: [u8; 4]) -> u32 {
-            ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) |
-                (buf[3] as u32)
-        }
-
-        // Read the magic number from the file.
-        file.read(&mut u16buf).map_err(
-            |err| PicoError::ReadFailed(1002, err),
-        )?;
-        let magic = tou16(u16buf);
-        if magic != ::magic() {
-            return Err(PicoError::NotPico(magic));
-        }
-
-        // Read the version numbers from the file.
-        file.read(&mut u16buf).map_err(
-            |err| PicoError::ReadFailed(1003, err),
+    /// Initialize from an existing, open, Pico-encoded file, applying the
+    /// given limits.
+    pub(crate) fn open_with(mut file: T, options: &PicoOpenOptions) -> Result<Pico<T>> {
+        // The header is at the start of the file, wherever the file is
+        // currently positioned.
+        file.seek(SeekFrom::Start(0)).map_err(
+            |err| PicoError::SeekFailed(1027, err),
         )?;
-        let major = tou16(u16buf);
-        file.read(&mut u16buf).map_err(
-            |err| PicoError::ReadFailed(1004, err),
+        let header = options.read_header(&mut file)?;
+        let length = file.seek(SeekFrom::End(0)).map_err(
+            |err| PicoError::SeekFailed(1045, err),
         )?;
-        let minor = tou16(u16buf);
-        if major > MAJOR {
-            return Err(PicoError::BadVersion(major, minor));
-        }
+        header.check_length(length)?;
+        let known_len = length.saturating_sub(header.offset as u64);
 
-        // Read the offset from the file.
-        file.read(&mut u32buf).map_err(
-            |err| PicoError::ReadFailed(1005, err),
-        )?;
-        let offset = tou32(u32buf);
-
-        // Read the hash from the file.
-        let mut hash = [0u8; HASH_LEN];
-        file.read(&mut hash).map_err(
-            |err| PicoError::ReadFailed(1006, err),
-        )?;
+        // Done.
+        Ok(Pico {
+            cipher: header.get_cipher()?.cipher(&header.key),
+            header,
+            is_hash_valid: true,
+            hash_context: None,
+            hashed: 0,
+            known_len: Some(known_len),
+            unflushed: None,
+            file: Some(file),
+        })
+    }
 
-        // Read the key length from the file.
-        file.read(&mut u16buf).map_err(
-            |err| PicoError::ReadFailed(1007, err),
-        )?;
-        let keylen = tou16(u16buf);
-        if keylen == 0 {
-            return Err(PicoError::KeyError);
-        }
+    /// Determine whether there are changes that have not been flushed.
+    pub fn is_dirty(&self) -> bool {
+        self.unflushed.is_some()
+    }
 
-        // Read the key.
-        let mut key = vec![0u8; keylen as usize];
-        file.read(&mut key).map_err(|err| {
-            PicoError::ReadFailed(1008, err)
-        })?;
+    /// Flush any changes and close the file.  Unlike dropping the file,
+    /// this reports any error.
+    pub fn close(self) -> Result<()> {
+        self.into_inner().map(|_| ())
+    }
 
-        // Compute the metadata start and length.
-        let md_start = KEY_POS + keylen as usize;
-        if (offset as usize) < md_start {
-            return Err(PicoError::BadOffset(offset, md_start as u32));
+    /// Flush any changes and return the underlying file.
+    pub fn into_inner(mut self) -> Result<T> {
+        if let Some(flush) = self.unflushed.take() {
+            flush(&mut self)?;
         }
-        let md_length = offset as usize - md_start;
 
-        // Done.
-        Ok(Pico {
-            major: major,
-            minor: minor,
-            offset: offset,
-            key: key,
-            hash: hash,
-            md_length: md_length,
-            md_start: md_start,
-            is_hash_valid: true,
-            file: file,
-        })
+        Ok(self.file.take().expect(NO_FILE))
     }
 
-    /// Write everything to the file.  This may force computation of the hash.
-    pub fn flush(&mut self) -> Result<()> {
-        self.check_hash()?;
-        self.write_header()?;
-        self.file.flush().map_err(
-            |err| PicoError::WriteFailed(1009, err),
-        )?;
-        Ok(())
+    /// Get the underlying file.
+    fn file(&mut self) -> &mut T {
+        self.file.as_mut().expect(NO_FILE)
     }
 
     /// Get the number of bytes reserved for metadata.
     pub fn get_md_length(&self) -> u32 {
-        self.md_length as u32
+        self.header.md_length
     }
 
     /// Obtain some part of the stored metadata.
@@ -338,7 +289,7 @@ impl<T: Seek + Read + Write> Pico<T> {
         if mdlen == 0 || start >= mdlen { return Ok(0); }
 
         // Compute the true offset to the metadata.
-        let true_offset = start as usize + self.md_start;
+        let true_offset = start as usize + self.header.md_start();
 
         // Compute the maximum number of bytes that can be read from the
         // metadata, and then figure out how many bytes we actually need to
@@ -349,12 +300,12 @@ impl<T: Seek + Read + Write> Pico<T> {
         }
 
         // Seek to the true offset.
-        self.file
+        self.file()
             .seek(SeekFrom::Start(true_offset as u64))
             .map_err(|err| PicoError::SeekFailed(1010, err))?;
 
         // Read the requested number of bytes from the metadata.
-        let count = self.file.read(&mut buffer[0..max]).map_err(|err| {
+        let count = read_fully(self.file(), &mut buffer[0..max]).map_err(|err| {
             PicoError::ReadFailed(1011, err)
         })?;
 
@@ -362,6 +313,172 @@ impl<T: Seek + Read + Write> Pico<T> {
         Ok(count)
     }
 
+    /// Read the metadata section as structured metadata.  See `Metadata`
+    /// for the encoding, and for how legacy raw metadata is read.
+    pub fn read_metadata_map(&mut self) -> Result<Metadata> {
+        let mut buffer = vec![0u8; self.get_md_length() as usize];
+        let count = self.get_metadata(0, &mut buffer)?;
+        buffer.truncate(count);
+        Metadata::decode(&buffer)
+    }
+
+    /// Get raw, unencrypted data from the file.
+    ///
+    /// # Arguments
+    /// * `position` - Zero-based index within the data.
+    /// * `buffer`   - The buffer to get the data.
+    ///
+    /// The buffer is filled, unless the end of the data is reached first.
+    /// The number of bytes read is returned.
+    pub fn get(&mut self, position: usize, buffer: &mut [u8]) -> Result<usize> {
+        // Compute the true offset to the data.
+        let true_offset = position + self.get_offset() as usize;
+
+        // Seek to the true offset.
+        self.file()
+            .seek(SeekFrom::Start(true_offset as u64))
+            .map_err(|err| PicoError::SeekFailed(1014, err))?;
+
+        // Read the requested number of bytes from the data.
+        let count = read_fully(self.file(), buffer).map_err(
+            |err| PicoError::ReadFailed(1015, err),
+        )?;
+
+        // Decrypt the data received.
+        self.cipher.apply(position, &mut buffer[0..count]);
+
+        // Success.
+        Ok(count)
+    }
+
+    /// Check the integrity of the data.  Every hash of the data stored in
+    /// the header is computed and compared to the stored value.  If any
+    /// differ, then `PicoError::HashMismatch` is returned.
+    ///
+    /// If the data has been modified, call `flush` first so that the
+    /// stored hashes are up to date.
+    pub fn verify(&mut self) -> Result<()> {
+        let (context, _) = self.scan_hash()?;
+        context.check(&self.header)
+    }
+
+    /// Compute the hashes of the data.  To do that, read back through the
+    /// entire data segment, decrypt it, and compute the hashes.  The hash
+    /// context and the number of bytes read are returned.
+    fn scan_hash(&mut self) -> Result<(Hashes, usize)> {
+        let mut position: usize = 0;
+        let mut buffer: [u8; CHUNK_SIZE] = [0; CHUNK_SIZE];
+        let mut context = Hashes::new(&self.header);
+        loop {
+            let num = self.get(position, &mut buffer)?;
+            if num == 0 {
+                break;
+            }
+            context.consume(&buffer[0..num]);
+            position += num;
+        }
+        Ok((context, position))
+    }
+
+    /// Get the number of data bytes stored in the file.  This is computed
+    /// from the length of the underlying file when first needed, and then
+    /// kept up to date as data is written.
+    pub fn data_len(&mut self) -> Result<u64> {
+        if let Some(length) = self.known_len {
+            return Ok(length);
+        }
+        let end = self.file().seek(SeekFrom::End(0)).map_err(
+            |err| PicoError::SeekFailed(1026, err),
+        )?;
+        let length = end.saturating_sub(self.get_offset() as u64);
+        self.known_len = Some(length);
+        Ok(length)
+    }
+}
+
+impl<T: Seek + Read + Write> Pico<T> {
+    /// Create a new Pico-encoded file.  Use `PicoBuilder` for more options,
+    /// including a random key.
+    ///
+    /// # Arguments
+    /// * `file`      - An open file for writing that must support `seek`.
+    /// * `key`       - The encryption key to use.  If this is empty,
+    ///   `PicoError::KeyError` is returned.
+    /// * `md_length` - The number of bytes to reserve for metadata.  Can be zero.
+    pub fn new(file: T, key: Vec<u8>, md_length: u32) -> Result<Pico<T>> {
+        PicoBuilder::new().key(key).reserve(md_length).create(file)
+    }
+
+    /// Create a new Pico-encoded file with the given header.  This allows
+    /// choosing the version of the encoding, the hashes to store, and
+    /// adding header extensions.  The hashes in the header are ignored,
+    /// and the offset is recomputed.
+    ///
+    /// # Arguments
+    /// * `file`   - An open file for writing that must support `seek`.
+    /// * `header` - The header to write.
+    pub fn from_header(file: T, mut header: PicoHeader) -> Result<Pico<T>> {
+        PicoHeader::check_key(&header.key)?;
+        header.hash = [0; HASH_LEN];
+        header.update_offset()?;
+        let mut pico = Pico {
+            cipher: header.get_cipher()?.cipher(&header.key),
+            hash_context: Some(Hashes::new(&header)),
+            header,
+            is_hash_valid: false,
+            hashed: 0,
+            known_len: None,
+            unflushed: None,
+            file: Some(file),
+        };
+        pico.mark_dirty();
+        pico.write_header()?;
+
+        // Clear the metadata section, so the file always extends at least
+        // to the start of the data.
+        let md_start = pico.header.md_start() as u64;
+        let md_length = pico.header.md_length as u64;
+        pico.zero_bytes(md_start, md_length)?;
+        pico.file().flush().map_err(
+            |err| PicoError::WriteFailed(1001, err),
+        )?;
+        Ok(pico)
+    }
+
+    /// Set the hashes of the data, when they have been computed elsewhere.
+    /// The data must not be changed through this structure afterward
+    /// unless the hashes are recomputed.
+    pub(crate) fn set_hash(&mut self, hashes: &Hashes) -> Result<()> {
+        hashes.store(&mut self.header)?;
+        self.is_hash_valid = true;
+        self.hash_context = None;
+        Ok(())
+    }
+
+    /// Get a new running hash suitable for computing the hashes stored in
+    /// this file.
+    pub(crate) fn new_hashes(&self) -> Hashes {
+        Hashes::new(&self.header)
+    }
+
+    /// Write everything to the file.  This may force computation of the hash.
+    pub fn flush(&mut self) -> Result<()> {
+        self.check_hash()?;
+        self.write_header()?;
+        self.file().flush().map_err(
+            |err| PicoError::WriteFailed(1009, err),
+        )?;
+        self.unflushed = None;
+        Ok(())
+    }
+
+    /// Note that the data has changed, so the hashes must be recomputed and
+    /// the header written before the file is closed.
+    fn mark_dirty(&mut self) {
+        self.is_hash_valid = false;
+        self.unflushed = Some(Pico::flush);
+    }
+
     /// Write into the metadata section.
     ///
     /// # Arguments
@@ -378,7 +495,7 @@ impl<T: Seek + Read + Write> Pico<T> {
         if mdlen == 0 || start >= mdlen { return Ok(0); }
 
         // Compute the true offset to the metadata.
-        let true_offset = start as usize + self.md_start;
+        let true_offset = start as usize + self.header.md_start();
 
         // Compute the maximum number of bytes that can be written to the
         // metadata, and then figure out how many bytes we actually need to
@@ -389,52 +506,38 @@ impl<T: Seek + Read + Write> Pico<T> {
         }
 
         // If nothing to write, stop now.
-        if max <= 0 {
+        if max == 0 {
             return Ok(0);
         }
 
         // Seek to the true offset.
-        self.file
+        self.file()
             .seek(SeekFrom::Start(true_offset as u64))
             .map_err(|err| PicoError::SeekFailed(1012, err))?;
 
         // Write the requested number of bytes to the metadata.
-        let count = self.file.write(&buffer[0..max]).map_err(|err| {
+        self.file().write_all(&buffer[0..max]).map_err(|err| {
             PicoError::WriteFailed(1013, err)
         })?;
 
         // Success.
-        Ok(count)
+        Ok(max)
     }
 
-    /// Get raw, unencrypted data from the file.
-    ///
-    /// # Arguments
-    /// * `position` - Zero-based index within the data.
-    /// * `buffer`   - The buffer to get the data.
-    ///
-    /// If possible, the buffer is filled.  The number of bytes read is
-    /// returned.
-    pub fn get(&mut self, position: usize, buffer: &mut [u8]) -> Result<usize> {
-        // Compute the true offset to the data.
-        let true_offset = position + self.get_offset() as usize;
-
-        // Seek to the true offset.
-        self.file
-            .seek(SeekFrom::Start(true_offset as u64))
-            .map_err(|err| PicoError::SeekFailed(1014, err))?;
-
-        // Read the requested number of bytes from the data.
-        let count = self.file.read(buffer).map_err(
-            |err| PicoError::ReadFailed(1015, err),
-        )?;
-
-        // Decrypt the data received.
-        let key = self.get_key();
-        crypt(position, buffer, &key);
-
-        // Success.
-        Ok(count)
+    /// Write structured metadata, replacing the entire metadata section.
+    /// Any space after the encoded metadata is cleared.  If the encoded
+    /// metadata does not fit in the space reserved for metadata, then
+    /// `PicoError::MetadataTooLarge` is returned and nothing is written.
+    /// Use `resize_metadata` to make room.
+    pub fn write_metadata_map(&mut self, metadata: &Metadata) -> Result<()> {
+        let mut encoded = metadata.encode()?;
+        let reserved = self.get_md_length();
+        if encoded.len() > reserved as usize {
+            return Err(PicoError::MetadataTooLarge(encoded.len() as u32, reserved));
+        }
+        encoded.resize(reserved as usize, 0);
+        self.put_metadata(0, &encoded)?;
+        Ok(())
     }
 
     /// Encrypt and store the given data in the file.  Note that the data is
@@ -444,29 +547,104 @@ impl<T: Seek + Read + Write> Pico<T> {
     /// * `position` - Zero-based index within the data.
     /// * `buffer`   - The data to encrypt and write.
     ///
-    /// If possible, the entire buffer is written.  The number of bytes written
-    /// is returned.
+    /// The entire buffer is written, and its length is returned.  If the
+    /// write fails, part of the buffer may have been written, and the hash
+    /// is recomputed when the file is flushed.
+    ///
+    /// If the position is past the end of the data, the gap is first filled
+    /// with encrypted zeros, so that it reads back as zeros.
     pub fn put(&mut self, position: usize, buffer: &mut [u8]) -> Result<usize> {
+        // Fill any gap between the end of the data and the position.
+        let length = self.data_len()?;
+        if position as u64 > length {
+            self.fill_zeros(length, position as u64)?;
+        }
+
         // Compute the true offset to the data.
         let true_offset = position + self.get_offset() as usize;
 
         // Seek to the true offset.
-        self.file
+        self.file()
             .seek(SeekFrom::Start(true_offset as u64))
             .map_err(|err| PicoError::SeekFailed(1016, err))?;
 
+        // If this write continues a sequential run from the start of the
+        // data, update the running hash.  Otherwise the hash must be
+        // recomputed from the file.
+        if position == self.hashed {
+            if let Some(ref mut context) = self.hash_context {
+                context.consume(&*buffer);
+                self.hashed += buffer.len();
+            }
+        } else {
+            self.hash_context = None;
+        }
+
         // Encrypt the data to be sent.
-        let key = self.get_key();
-        crypt(position, buffer, &key);
+        self.cipher.apply(position, buffer);
 
-        // Write the requested number of bytes to the data.
-        self.is_hash_valid = false;
-        let count = self.file.write(buffer).map_err(|err| {
-            PicoError::ReadFailed(1017, err)
-        })?;
+        // Write the requested number of bytes to the data.  If that fails,
+        // the running hash no longer matches the file.
+        self.mark_dirty();
+        if let Err(err) = self.file().write_all(buffer) {
+            self.hash_context = None;
+            self.known_len = None;
+            return Err(PicoError::WriteFailed(1017, err));
+        }
 
-        // Success.
-        Ok(count)
+        // Success.  The data may now extend further.
+        self.extend_len(position as u64 + buffer.len() as u64);
+        Ok(buffer.len())
+    }
+
+    /// Write to the end of the data.  The entire buffer is written, and its
+    /// length is returned.
+    ///
+    /// # Arguments
+    /// * `buffer` - The data to write.  It is copied before it is encrypted.
+    pub fn append(&mut self, buffer: &[u8]) -> Result<usize> {
+        let position = self.data_len()?;
+        self.put(position as usize, &mut buffer.to_vec())
+    }
+
+    /// Write encrypted zeros to the data from `start` up to `end`.  If the
+    /// running hash covers the data up to `start`, it is extended to cover
+    /// the zeros.
+    fn fill_zeros(&mut self, start: u64, end: u64) -> Result<()> {
+        let offset = self.get_offset() as u64;
+        self.file().seek(SeekFrom::Start(offset + start)).map_err(
+            |err| PicoError::SeekFailed(1046, err),
+        )?;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut position = start;
+        while position < end {
+            let count = (end - position).min(CHUNK_SIZE as u64) as usize;
+            let chunk = &mut buffer[0..count];
+            for byte in chunk.iter_mut() {
+                *byte = 0;
+            }
+            if self.hashed as u64 == position {
+                if let Some(ref mut context) = self.hash_context {
+                    context.consume(chunk);
+                    self.hashed += count;
+                }
+            }
+            self.cipher.apply(position as usize, chunk);
+            self.mark_dirty();
+            if let Err(err) = self.file().write_all(chunk) {
+                self.hash_context = None;
+                self.known_len = None;
+                return Err(PicoError::WriteFailed(1047, err));
+            }
+            position += count as u64;
+        }
+        self.extend_len(end);
+        Ok(())
+    }
+
+    /// Note that the data extends at least to the given position.
+    fn extend_len(&mut self, end: u64) {
+        self.known_len = self.known_len.map(|length| length.max(end));
     }
 
     fn check_hash(&mut self) -> Result<()> {
@@ -475,72 +653,219 @@ impl<T: Seek + Read + Write> Pico<T> {
             return Ok(());
         }
 
-        // Re-compute the hash.  To do that, read back through the entire
-        // data segment, decrypt it, compute the hash, and write it to the
-        // stored header.
-
-        let mut position: usize = 0;
-        let mut buffer: [u8; CHUNK_SIZE] = [0; CHUNK_SIZE];
-        let mut context = md5::Context::new();
-        loop {
-            let num = self.get(position, &mut buffer)?;
-            if num == 0 {
-                break;
+        // If all the data was written sequentially, the running hash
+        // already covers it.
+        if self.hash_context.is_some() && self.hashed as u64 == self.data_len()? {
+            if let Some(ref context) = self.hash_context {
+                context.store(&mut self.header)?;
             }
-            context.consume(&buffer[0..num]);
-            position = position + num;
+            self.is_hash_valid = true;
+            return Ok(());
         }
-        self.hash = *context.compute();
+
+        // Re-compute the hash.  Keep the running hash so that later
+        // sequential writes at the end can extend it.
+        let (context, position) = self.scan_hash()?;
+        context.store(&mut self.header)?;
+        self.hash_context = Some(context);
+        self.hashed = position;
         self.is_hash_valid = true;
         Ok(())
     }
 
+    /// Overwrite raw bytes in the file with zeros.
+    fn zero_bytes(&mut self, start: u64, length: u64) -> Result<()> {
+        let buffer = vec![0u8; CHUNK_SIZE];
+        self.file().seek(SeekFrom::Start(start)).map_err(
+            |err| PicoError::SeekFailed(1037, err),
+        )?;
+        let mut done: u64 = 0;
+        while done < length {
+            let count = (length - done).min(CHUNK_SIZE as u64);
+            self.file().write_all(&buffer[0..count as usize]).map_err(
+                |err| PicoError::WriteFailed(1038, err),
+            )?;
+            done += count;
+        }
+        Ok(())
+    }
+
     fn write_header(&mut self) -> Result<()> {
         // Seek to the start of the file.
-        self.file.seek(SeekFrom::Start(0)).map_err(|err| {
+        let file = self.file.as_mut().expect(NO_FILE);
+        file.seek(SeekFrom::Start(0)).map_err(|err| {
             PicoError::SeekFailed(1018, err)
         })?;
+        self.header.write_to(file)
+    }
+}
 
-        // Write the magic number.
-        let item = ::magic().get_bytes();
-        self.file.write(&item).map_err(|err| {
-            PicoError::WriteFailed(1019, err)
-        })?;
+impl<T: Seek + Read + Write + SetLen> Pico<T> {
+    /// Change the number of bytes reserved for metadata.  The data is moved
+    /// to follow the new metadata section.  Data is encrypted according to
+    /// its position within the data, so it is not re-encrypted.  If the
+    /// metadata grows, the existing metadata is kept and the new bytes are
+    /// zero.  If it shrinks, the metadata is truncated.
+    ///
+    /// The file is modified in place, so if this fails part way the file is
+    /// damaged.  Use `file::resize_metadata` to resize a copy instead.
+    ///
+    /// # Arguments
+    /// * `md_length` - The number of bytes to reserve for metadata.
+    pub fn resize_metadata(&mut self, md_length: u32) -> Result<()> {
+        let length = self.data_len()?;
+        let old_offset = self.get_offset() as u64;
+        let old_md_length = self.header.md_length;
+        self.header.md_length = md_length;
+        if let Err(err) = self.header.update_offset() {
+            self.header.md_length = old_md_length;
+            return Err(err);
+        }
+        let new_offset = self.get_offset() as u64;
 
-        // Write the version number.
-        let (major, minor) = self.get_version();
-        let item = major.get_bytes();
-        self.file.write(&item).map_err(|err| {
-            PicoError::WriteFailed(1020, err)
-        })?;
-        let item = minor.get_bytes();
-        self.file.write(&item).map_err(|err| {
-            PicoError::WriteFailed(1021, err)
-        })?;
+        // Move the data, and then clear any new metadata bytes, which
+        // may have held data.
+        self.move_bytes(old_offset, new_offset, length)?;
+        if md_length > old_md_length {
+            let start = self.header.md_start() as u64 + old_md_length as u64;
+            self.zero_bytes(start, (md_length - old_md_length) as u64)?;
+        }
+        if new_offset < old_offset {
+            self.file().set_len(new_offset + length).map_err(
+                |err| PicoError::WriteFailed(1031, err),
+            )?;
+        }
 
-        // Write the offset to the data.
-        let item = self.get_offset().get_bytes();
-        self.file.write(&item).map_err(|err| {
-            PicoError::WriteFailed(1022, err)
-        })?;
+        // Write the new offset.
+        self.write_header()?;
+        self.file().flush().map_err(
+            |err| PicoError::WriteFailed(1032, err),
+        )?;
+        Ok(())
+    }
 
-        // Write the hash.
-        let item = self.get_hash();
-        self.file.write(item.as_slice()).map_err(|err| {
-            PicoError::WriteFailed(1023, err)
-        })?;
+    /// Change the key used to encrypt the data.  The data is decrypted with
+    /// the old key and encrypted with the new key one chunk at a time, in
+    /// memory, so the plaintext is never written to the file.  If the new
+    /// key has a different length, the metadata and data are moved to
+    /// follow the new header.  The metadata, the hashes, and the cipher are
+    /// kept.
+    ///
+    /// The file is modified in place, so if this fails part way the file is
+    /// damaged.  Use `file::rekey` to rekey a copy instead.
+    ///
+    /// # Arguments
+    /// * `key` - The new encryption key.  This cannot be empty.
+    pub fn rekey(&mut self, key: Vec<u8>) -> Result<()> {
+        PicoHeader::check_key(&key)?;
 
-        // Write the key length and then the key.
-        let key = self.get_key();
-        let item = (key.len() as u16).get_bytes();
-        self.file.write(&item).map_err(|err| {
-            PicoError::WriteFailed(1024, err)
-        })?;
-        self.file.write(key.as_slice()).map_err(|err| {
-            PicoError::WriteFailed(1025, err)
-        })?;
+        // Bring the hashes up to date first, since they are computed from
+        // the data using the old key.
+        self.flush()?;
+        let length = self.data_len()?;
+        let old_md_start = self.header.md_start() as u64;
+        let kind = self.header.get_cipher()?;
+        let old_key = mem::replace(&mut self.header.key, key);
+        if let Err(err) = self.header.update_offset() {
+            self.header.key = old_key;
+            return Err(err);
+        }
+        let old_cipher = kind.cipher(&old_key);
+        self.cipher = kind.cipher(&self.header.key);
+        let new_md_start = self.header.md_start() as u64;
+        let offset = self.get_offset() as u64;
+
+        // Move the metadata and data to follow the new header, and then
+        // re-encrypt the data where it now lies.
+        let md_length = self.header.md_length as u64;
+        self.move_bytes(old_md_start, new_md_start, md_length + length)?;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut position: u64 = 0;
+        while position < length {
+            let count = (length - position).min(CHUNK_SIZE as u64);
+            let chunk = &mut buffer[0..count as usize];
+            self.file().seek(SeekFrom::Start(offset + position)).map_err(
+                |err| PicoError::SeekFailed(1039, err),
+            )?;
+            self.file().read_exact(chunk).map_err(
+                |err| PicoError::ReadFailed(1040, err),
+            )?;
+            old_cipher.apply(position as usize, chunk);
+            self.cipher.apply(position as usize, chunk);
+            self.file().seek(SeekFrom::Start(offset + position)).map_err(
+                |err| PicoError::SeekFailed(1041, err),
+            )?;
+            self.file().write_all(chunk).map_err(
+                |err| PicoError::WriteFailed(1042, err),
+            )?;
+            position += count;
+        }
+        if new_md_start < old_md_start {
+            self.file().set_len(offset + length).map_err(
+                |err| PicoError::WriteFailed(1043, err),
+            )?;
+        }
+
+        // Write the new key and offset.
+        self.write_header()?;
+        self.file().flush().map_err(
+            |err| PicoError::WriteFailed(1044, err),
+        )?;
+        Ok(())
+    }
+
+    /// Truncate or extend the data to the given number of bytes.  If the
+    /// data grows, the new bytes read back as zeros.  The hashes are
+    /// recomputed when the file is flushed.
+    ///
+    /// # Arguments
+    /// * `length` - The number of data bytes to keep.
+    pub fn set_data_len(&mut self, length: u64) -> Result<()> {
+        let old_length = self.data_len()?;
+        if length > old_length {
+            return self.fill_zeros(old_length, length);
+        }
+        if length < old_length {
+            let offset = self.get_offset() as u64;
+            self.known_len = None;
+            self.file().set_len(offset + length).map_err(
+                |err| PicoError::WriteFailed(1048, err),
+            )?;
+            self.known_len = Some(length);
+            self.mark_dirty();
+            if self.hashed as u64 > length {
+                self.hash_context = None;
+            }
+        }
+        Ok(())
+    }
 
-        // If we get here, success!
+    /// Move raw bytes within the file.  The source and destination may
+    /// overlap.
+    fn move_bytes(&mut self, from: u64, to: u64, length: u64) -> Result<()> {
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut done: u64 = 0;
+        while from != to && done < length {
+            // Work from the start when moving toward the start of the file,
+            // and from the end otherwise, so that no byte is overwritten
+            // before it is moved.
+            let count = (length - done).min(CHUNK_SIZE as u64);
+            let start = if to < from { done } else { length - done - count };
+            let chunk = &mut buffer[0..count as usize];
+            self.file().seek(SeekFrom::Start(from + start)).map_err(
+                |err| PicoError::SeekFailed(1033, err),
+            )?;
+            self.file().read_exact(chunk).map_err(
+                |err| PicoError::ReadFailed(1034, err),
+            )?;
+            self.file().seek(SeekFrom::Start(to + start)).map_err(
+                |err| PicoError::SeekFailed(1035, err),
+            )?;
+            self.file().write_all(chunk).map_err(
+                |err| PicoError::WriteFailed(1036, err),
+            )?;
+            done += count;
+        }
         Ok(())
     }
 }
@@ -550,6 +875,15 @@ mod test {
     use std::fs::OpenOptions;
     use std::fs::create_dir_all;
     use std::fs::remove_file;
+    use std::fs::read;
+    use std::io::Cursor;
+    use header::{HashAlgorithm, HeaderFormat, PicoHeader};
+    use crypt::CipherKind;
+    use metadata::{Metadata, MetadataValue};
+    use md5;
+    use errors::PicoError;
+    use std::io;
+    use std::io::{Read, Write, Seek, SeekFrom};
     use super::Pico;
 
     #[test]
@@ -680,7 +1014,7 @@ mod test {
                 .open("_test/data_test_1.pico")
                 .unwrap();
             let mut pico = Pico::new(file, vec![0x55, 0x21, 0xe4, 0x9a], 10).unwrap();
-            let mut indata = b"Martindale".clone();
+            let mut indata = *b"Martindale";
             pico.put(10, &mut indata).unwrap();
             pico.flush().unwrap();
         }
@@ -692,10 +1026,505 @@ mod test {
                 .open("_test/data_test_1.pico")
                 .unwrap();
             let mut pico = Pico::open(file).unwrap();
-            let mut data = [0u8; 20];
+            let mut data = [0xffu8; 20];
             assert_eq!(pico.get(0, &mut data).unwrap(), 20);
+            assert_eq!(&data[..10], &[0u8; 10]);
             assert_eq!(&data[10..], b"Martindale");
+            pico.verify().unwrap();
         }
         remove_file("_test/data_test_1.pico").unwrap();
     }
+
+    #[test]
+    fn read_only_test() {
+        create_dir_all("_test").unwrap();
+        {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .read(true)
+                .open("_test/read_only_test.pico")
+                .unwrap();
+            let mut pico = Pico::new(file, vec![0x55, 0x21, 0xe4, 0x9a], 10).unwrap();
+            pico.put_metadata(0, b"Martindale").unwrap();
+            let mut indata = *b"Martindale";
+            pico.put(0, &mut indata).unwrap();
+            pico.flush().unwrap();
+        }
+        let before = read("_test/read_only_test.pico").unwrap();
+        {
+            let file = OpenOptions::new()
+                .read(true)
+                .open("_test/read_only_test.pico")
+                .unwrap();
+            let mut pico = Pico::open(file).unwrap();
+            let mut data = [0u8; 10];
+            assert_eq!(pico.get(0, &mut data).unwrap(), 10);
+            assert_eq!(&data, b"Martindale");
+            assert_eq!(pico.get_metadata(0, &mut data).unwrap(), 10);
+            assert_eq!(&data, b"Martindale");
+            let mut output: Vec<u8> = Vec::new();
+            pico.dump_header(&mut output, &HeaderFormat::YAML);
+            assert!(!output.is_empty());
+        }
+        assert_eq!(read("_test/read_only_test.pico").unwrap(), before);
+        remove_file("_test/read_only_test.pico").unwrap();
+    }
+
+    #[test]
+    fn hash_test_2() {
+        // Sequential writes use the running hash.
+        let text = b"Martindale".repeat(1000);
+        let mut pico = Pico::new(Cursor::new(Vec::new()), vec![0x55, 0x21, 0xe4, 0x9a], 10).unwrap();
+        let mut position = 0;
+        for chunk in text.chunks(999) {
+            let mut chunk = chunk.to_vec();
+            position += pico.put(position, &mut chunk).unwrap();
+        }
+        assert!(pico.hash_context.is_some());
+        assert_eq!(pico.hashed, text.len());
+        pico.flush().unwrap();
+        assert_eq!(pico.get_hash(), md5::compute(&text).to_vec());
+
+        // An out-of-order write forces the hash to be recomputed.
+        let mut indata = *b"Newcomb";
+        pico.put(3, &mut indata).unwrap();
+        assert!(pico.hash_context.is_none());
+        pico.flush().unwrap();
+        let mut expected = text.clone();
+        expected[3..10].copy_from_slice(b"Newcomb");
+        assert_eq!(pico.get_hash(), md5::compute(&expected).to_vec());
+
+        // Appending after a recomputation extends the running hash.
+        let mut indata = *b"Newcomb";
+        pico.put(expected.len(), &mut indata).unwrap();
+        assert!(pico.hash_context.is_some());
+        pico.flush().unwrap();
+        expected.extend_from_slice(b"Newcomb");
+        assert_eq!(pico.get_hash(), md5::compute(&expected).to_vec());
+    }
+
+    #[test]
+    fn verify_test() {
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let mut pico = Pico::new(&mut encoded, vec![0x55, 0x21, 0xe4, 0x9a], 0).unwrap();
+            let mut indata = *b"Martindale";
+            pico.put(0, &mut indata).unwrap();
+            pico.flush().unwrap();
+            pico.verify().unwrap();
+        }
+
+        // Flip a bit in the data.
+        let last = encoded.get_ref().len() - 1;
+        encoded.get_mut()[last] ^= 0x01;
+        let mut pico = Pico::open(&mut encoded).unwrap();
+        match pico.verify() {
+            Err(PicoError::HashMismatch { algorithm: HashAlgorithm::MD5, stored, computed }) => {
+                assert_eq!(stored, md5::compute(b"Martindale").to_vec());
+                assert_eq!(computed, md5::compute(b"Martindald").to_vec());
+            }
+            _ => panic!("Expected a hash mismatch."),
+        }
+    }
+
+    #[test]
+    fn version_test() {
+        // A version 1.0 file has the key immediately before the metadata.
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let header = PicoHeader::new_version(1, 0, vec![0x55, 0x21], 7).unwrap();
+            let mut pico = Pico::from_header(&mut encoded, header).unwrap();
+            pico.put_metadata(0, b"Newcomb").unwrap();
+            let mut indata = *b"Martindale";
+            pico.put(0, &mut indata).unwrap();
+            pico.flush().unwrap();
+        }
+        assert_eq!(&encoded.get_ref()[30..37], b"Newcomb");
+        let (data, _, header) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, b"Martindale");
+        assert_eq!(header.major, 1);
+
+        // A version 2.0 file keeps its extensions, including unknown ones.
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let mut header = PicoHeader::new(vec![0x55, 0x21], 7).unwrap();
+            header.set_extension(0xfff0, b"unknown".to_vec()).unwrap();
+            let mut pico = Pico::from_header(&mut encoded, header).unwrap();
+            pico.put_metadata(0, b"Newcomb").unwrap();
+            let mut indata = *b"Martindale";
+            pico.put(0, &mut indata).unwrap();
+            pico.flush().unwrap();
+        }
+        let mut pico = Pico::open(&mut encoded).unwrap();
+        assert_eq!(pico.get_version(), (2, 0));
+        assert_eq!(pico.get_header().get_extension(0xfff0), Some(&b"unknown"[..]));
+        let mut metadata = [0u8; 7];
+        pico.get_metadata(0, &mut metadata).unwrap();
+        assert_eq!(&metadata, b"Newcomb");
+        pico.verify().unwrap();
+    }
+
+    #[test]
+    fn hash_test_3() {
+        use sha1::Sha1;
+        use sha2::{Digest, Sha256};
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let mut header = PicoHeader::new(vec![0x55, 0x21, 0xe4, 0x9a], 0).unwrap();
+            header.enable_hash(HashAlgorithm::SHA1).unwrap();
+            header.enable_hash(HashAlgorithm::SHA256).unwrap();
+            let mut pico = Pico::from_header(&mut encoded, header).unwrap();
+            let mut indata = *b"Newcomb";
+            pico.put(3, &mut indata).unwrap();
+            let mut indata = *b"Mar";
+            pico.put(0, &mut indata).unwrap();
+            pico.flush().unwrap();
+            assert_eq!(pico.get_sha1().unwrap(), Sha1::digest(b"MarNewcomb").to_vec());
+            assert_eq!(pico.get_sha256().unwrap(), Sha256::digest(b"MarNewcomb").to_vec());
+            pico.verify().unwrap();
+        }
+
+        // Damage only the stored SHA-256.
+        let last = encoded.get_ref().len() - 11;
+        encoded.get_mut()[last] ^= 0x01;
+        let mut pico = Pico::open(&mut encoded).unwrap();
+        match pico.verify() {
+            Err(PicoError::HashMismatch { algorithm: HashAlgorithm::SHA256, .. }) => (),
+            _ => panic!("Expected a SHA-256 mismatch."),
+        }
+
+        // Files created with the default options store only MD5.
+        let encoded = ::encode_bytes(b"Martindale", vec![0x55], b"").unwrap();
+        let pico = Pico::open(Cursor::new(encoded)).unwrap();
+        assert_eq!(pico.get_sha256(), None);
+        assert_eq!(pico.get_sha1(), None);
+    }
+
+    #[test]
+    fn cipher_test() {
+        let text = b"Martindale".repeat(100);
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let mut header = PicoHeader::new(vec![0x55, 0x21, 0xe4, 0x9a], 0).unwrap();
+            header.set_cipher(CipherKind::CHACHA20).unwrap();
+            let mut pico = Pico::from_header(&mut encoded, header).unwrap();
+            let mut indata = text.clone();
+            pico.put(0, &mut indata).unwrap();
+            pico.flush().unwrap();
+        }
+
+        // The stored data is not the xor encryption of the text.
+        let xored = ::encode_bytes(&text, vec![0x55, 0x21, 0xe4, 0x9a], b"").unwrap();
+        let start = encoded.get_ref().len() - text.len();
+        assert_ne!(&encoded.get_ref()[start..], &xored[xored.len() - text.len()..]);
+
+        let mut pico = Pico::open(&mut encoded).unwrap();
+        pico.verify().unwrap();
+        let mut data = vec![0u8; 25];
+        pico.get(995, &mut data).unwrap();
+        assert_eq!(&data[..5], b"ndale");
+        pico.close().unwrap();
+        let (data, _, header) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, text);
+        assert_eq!(header.get_cipher().unwrap(), CipherKind::CHACHA20);
+    }
+
+    #[test]
+    fn resize_test() {
+        let text: Vec<u8> = (0..10_000usize).map(|index| (index * 31 % 251) as u8).collect();
+        let encoded = ::encode_bytes(&text, vec![0x55, 0x21, 0xe4], b"Newcomb").unwrap();
+        let mut encoded = Cursor::new(encoded);
+
+        // Grow the metadata.
+        {
+            let mut pico = Pico::open(&mut encoded).unwrap();
+            pico.resize_metadata(5000).unwrap();
+            assert_eq!(pico.put_metadata(7, b" Martindale").unwrap(), 11);
+        }
+        let (data, metadata, header) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, text);
+        assert_eq!(header.md_length, 5000);
+        assert_eq!(&metadata[0..18], b"Newcomb Martindale");
+        assert!(metadata[18..].iter().all(|byte| *byte == 0));
+
+        // Shrink the metadata.
+        {
+            let mut pico = Pico::open(&mut encoded).unwrap();
+            pico.resize_metadata(3).unwrap();
+            pico.verify().unwrap();
+        }
+        let (data, metadata, header) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, text);
+        assert_eq!(metadata, b"New");
+        assert_eq!(encoded.get_ref().len(), header.offset as usize + text.len());
+
+        // An offset that does not fit in 32 bits is rejected, and nothing
+        // is changed.
+        let before = encoded.get_ref().clone();
+        {
+            let mut pico = Pico::open(&mut encoded).unwrap();
+            match pico.resize_metadata(u32::MAX - 10) {
+                Err(PicoError::MetadataTooLarge(needed, _)) => assert_eq!(needed, u32::MAX - 10),
+                _ => panic!("Expected the metadata to be too large."),
+            }
+            assert_eq!(pico.get_md_length(), 3);
+        }
+        assert_eq!(encoded.get_ref(), &before);
+    }
+
+    #[test]
+    fn metadata_map_test() {
+        let mut metadata = Metadata::new();
+        metadata.insert("name", MetadataValue::Text("Martindale".to_string()));
+        metadata.insert("count", MetadataValue::Int(7));
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let mut pico = Pico::new(&mut encoded, vec![0x55, 0x21], 30).unwrap();
+            pico.put_metadata(0, &[0xff; 30]).unwrap();
+            match pico.write_metadata_map(&metadata) {
+                Err(PicoError::MetadataTooLarge(49, 30)) => (),
+                _ => panic!("Expected the metadata to be too large."),
+            }
+            pico.resize_metadata(64).unwrap();
+            pico.write_metadata_map(&metadata).unwrap();
+            pico.flush().unwrap();
+        }
+        let mut pico = Pico::open(&mut encoded).unwrap();
+        assert_eq!(pico.read_metadata_map().unwrap(), metadata);
+        let mut tail = [0xffu8; 15];
+        pico.get_metadata(49, &mut tail).unwrap();
+        assert_eq!(tail, [0u8; 15]);
+
+        // Legacy metadata is still readable.
+        let encoded = ::encode_bytes(b"", vec![0x55], b"Newcomb").unwrap();
+        let mut pico = Pico::open(Cursor::new(encoded)).unwrap();
+        assert_eq!(pico.read_metadata_map().unwrap().get(::LEGACY_KEY),
+            Some(&MetadataValue::Bytes(b"Newcomb".to_vec())));
+    }
+
+    #[test]
+    fn rekey_test() {
+        let text: Vec<u8> = (0..100_000usize).map(|index| (index * 31 % 251) as u8).collect();
+        let encoded = ::encode_bytes(&text, vec![0x55, 0x21, 0xe4], b"Newcomb").unwrap();
+        let mut encoded = Cursor::new(encoded);
+
+        // Lengthen the key.
+        let key: Vec<u8> = (0..40u8).collect();
+        {
+            let mut pico = Pico::open(&mut encoded).unwrap();
+            pico.rekey(key.clone()).unwrap();
+            pico.verify().unwrap();
+        }
+        let (data, metadata, header) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, text);
+        assert_eq!(metadata, b"Newcomb");
+        assert_eq!(header.key, key);
+        assert_eq!(header.hash, md5::compute(&text).0);
+
+        // Shorten the key, with a cipher other than xor.
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let mut header = PicoHeader::new(key, 7).unwrap();
+            header.set_cipher(CipherKind::CHACHA20).unwrap();
+            let mut pico = Pico::from_header(&mut encoded, header).unwrap();
+            pico.put_metadata(0, b"Newcomb").unwrap();
+            let mut indata = text.clone();
+            pico.put(0, &mut indata).unwrap();
+            pico.rekey(vec![0x9a]).unwrap();
+            match pico.rekey(vec![]) {
+                Err(PicoError::KeyError) => (),
+                _ => panic!("Expected a key error."),
+            }
+        }
+        let (data, metadata, header) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, text);
+        assert_eq!(metadata, b"Newcomb");
+        assert_eq!(header.key, vec![0x9a]);
+        assert_eq!(header.get_cipher().unwrap(), CipherKind::CHACHA20);
+        assert_eq!(encoded.get_ref().len(), header.offset as usize + text.len());
+    }
+
+    /// A file that reads and writes at most three bytes at a time, is
+    /// interrupted on every other call, and can be made to fail writes.
+    #[allow(dead_code)]
+    struct ShortIo {
+        inner: Cursor<Vec<u8>>,
+        interrupt: bool,
+        fail_writes: bool,
+    }
+
+    #[allow(dead_code)]
+    impl ShortIo {
+        fn interrupted(&mut self) -> bool {
+            self.interrupt = !self.interrupt;
+            self.interrupt
+        }
+    }
+
+    impl Read for ShortIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.interrupted() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+            }
+            let count = buf.len().min(3);
+            self.inner.read(&mut buf[..count])
+        }
+    }
+
+    impl Write for ShortIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.fail_writes {
+                return Err(io::Error::other("full"));
+            }
+            if self.interrupted() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+            }
+            let count = buf.len().min(3);
+            self.inner.write(&buf[..count])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for ShortIo {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn short_io_test() {
+        let text = b"Martindale".repeat(100);
+        let mut file = ShortIo { inner: Cursor::new(Vec::new()), interrupt: false, fail_writes: false };
+        {
+            let mut pico = Pico::new(&mut file, vec![0x55, 0x21, 0xe4, 0x9a], 10).unwrap();
+            assert_eq!(pico.put_metadata(0, b"Newcomb").unwrap(), 7);
+            let mut indata = text.clone();
+            assert_eq!(pico.put(0, &mut indata).unwrap(), text.len());
+            pico.flush().unwrap();
+        }
+        {
+            let mut pico = Pico::open(&mut file).unwrap();
+            pico.verify().unwrap();
+            let mut data = vec![0u8; text.len() + 10];
+            assert_eq!(pico.get(0, &mut data).unwrap(), text.len());
+            assert_eq!(&data[..text.len()], &text[..]);
+            let mut metadata = [0u8; 10];
+            assert_eq!(pico.get_metadata(0, &mut metadata).unwrap(), 10);
+            assert_eq!(&metadata, b"Newcomb\0\0\0");
+        }
+        let (data, _, _) = ::decode_bytes(file.inner.get_ref()).unwrap();
+        assert_eq!(data, text);
+
+        // Write failures are reported as such.
+        file.fail_writes = true;
+        let mut pico = Pico::open(&mut file).unwrap();
+        match pico.put(0, &mut b"Newcomb".to_vec()) {
+            Err(PicoError::WriteFailed(1017, _)) => (),
+            _ => panic!("Expected a failed write."),
+        }
+    }
+
+    #[test]
+    fn sparse_test() {
+        // Write past the end, both in order and out of order, and check
+        // that every gap reads back as zeros.
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let mut pico = ::PicoBuilder::new()
+                .key(vec![0x55, 0x21, 0xe4])
+                .cipher(CipherKind::CHACHA20)
+                .create(&mut encoded)
+                .unwrap();
+            pico.put(5, &mut b"Martindale".to_vec()).unwrap();
+            pico.put(10000, &mut b"Newcomb".to_vec()).unwrap();
+            pico.put(20, &mut b"Martindale".to_vec()).unwrap();
+            pico.flush().unwrap();
+            pico.verify().unwrap();
+        }
+        let mut expected = vec![0u8; 10007];
+        expected[5..15].copy_from_slice(b"Martindale");
+        expected[20..30].copy_from_slice(b"Martindale");
+        expected[10000..].copy_from_slice(b"Newcomb");
+        let (data, _, _) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn data_len_test() {
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let mut pico = Pico::new(&mut encoded, vec![0x55, 0x21, 0xe4], 10).unwrap();
+            assert_eq!(pico.data_len().unwrap(), 0);
+            assert_eq!(pico.append(b"Martindale").unwrap(), 10);
+            assert_eq!(pico.append(b"Newcomb").unwrap(), 7);
+            assert_eq!(pico.data_len().unwrap(), 17);
+
+            // Truncate into the hashed data, and then extend.
+            pico.set_data_len(6).unwrap();
+            assert_eq!(pico.data_len().unwrap(), 6);
+            pico.flush().unwrap();
+            pico.verify().unwrap();
+            pico.set_data_len(9).unwrap();
+            assert_eq!(pico.append(b"Newcomb").unwrap(), 7);
+            pico.flush().unwrap();
+            pico.verify().unwrap();
+        }
+        let (data, _, _) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, b"Martin\0\0\0Newcomb");
+
+        // Overwriting keeps the length, and writing past the end extends it.
+        {
+            let mut pico = Pico::open(&mut encoded).unwrap();
+            assert_eq!(pico.data_len().unwrap(), 16);
+            pico.put(6, &mut b"dal".to_vec()).unwrap();
+            assert_eq!(pico.data_len().unwrap(), 16);
+            pico.put(18, &mut b"Mar".to_vec()).unwrap();
+            assert_eq!(pico.data_len().unwrap(), 21);
+        }
+        let (data, _, _) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, b"MartindalNewcomb\0\0Mar");
+    }
+
+    #[test]
+    fn drop_test() {
+        // Changes are flushed when the file is dropped.
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let mut pico = Pico::new(&mut encoded, vec![0x55, 0x21, 0xe4], 0).unwrap();
+            assert!(pico.is_dirty());
+            pico.flush().unwrap();
+            assert!(!pico.is_dirty());
+            pico.append(b"Martindale").unwrap();
+            assert!(pico.is_dirty());
+        }
+        let (data, _, header) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, b"Martindale");
+        assert_eq!(header.hash, *md5::compute(b"Martindale"));
+
+        // Reading does not make the file dirty, and the file can be taken
+        // back after closing.
+        let mut pico = Pico::open(encoded).unwrap();
+        let mut data = [0u8; 10];
+        pico.get(0, &mut data).unwrap();
+        assert!(!pico.is_dirty());
+        pico.append(b"Newcomb").unwrap();
+        let encoded = pico.into_inner().unwrap();
+        let (data, _, _) = ::decode_bytes(encoded.get_ref()).unwrap();
+        assert_eq!(data, b"MartindaleNewcomb");
+
+        // Closing reports a failed flush.
+        let mut file = ShortIo { inner: encoded, interrupt: false, fail_writes: false };
+        let mut pico = Pico::open(&mut file).unwrap();
+        pico.append(b"Martindale").unwrap();
+        pico.file().fail_writes = true;
+        match pico.close() {
+            Err(PicoError::WriteFailed(_, _)) => (),
+            _ => panic!("Expected a failed write."),
+        }
+    }
 }
@@ -0,0 +1,436 @@
+//! A standard provenance record, describing where a sample came from.
+//!
+//! The provenance is stored in the structured metadata, under keys that
+//! start with `provenance.`, so it can live alongside other metadata.
+//! Every field is optional.
+//!
+//! ^ Key ^ Type ^ Meaning ^
+//! | `provenance.filename` | Text | Original file name |
+//! | `provenance.path` | Text | Original path |
+//! | `provenance.collected` | Timestamp | Collection time |
+//! | `provenance.submitter` | Text | Who submitted the sample |
+//! | `provenance.source` | Text | Source URL |
+//! | `provenance.case` | Text | Case identifier |
+//! | `provenance.tags` | Text | Free-form tags, one per line |
+//! | `provenance.mode` | Int | Permission bits of the original file |
+//! | `provenance.created` | Timestamp | Creation time of the original file |
+//! | `provenance.modified` | Timestamp | Modification time of the original file |
+//!
+//! ```
+//! use pico::{Metadata, Provenance};
+//!
+//! let mut provenance = Provenance::new();
+//! provenance.case = Some("1701".to_string());
+//! provenance.tags.push("phishing".to_string());
+//! let mut metadata = Metadata::new();
+//! provenance.store(&mut metadata).unwrap();
+//! assert_eq!(Provenance::from_metadata(&metadata), Some(provenance));
+//! ```
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use header::HeaderFormat;
+use errors::{PicoError, Result};
+use metadata::{Metadata, MetadataValue};
+
+/// Metadata key for the original file name.
+pub const PROVENANCE_FILENAME: &str = "provenance.filename";
+/// Metadata key for the original path.
+pub const PROVENANCE_PATH: &str = "provenance.path";
+/// Metadata key for the collection time.
+pub const PROVENANCE_COLLECTED: &str = "provenance.collected";
+/// Metadata key for the submitter.
+pub const PROVENANCE_SUBMITTER: &str = "provenance.submitter";
+/// Metadata key for the source URL.
+pub const PROVENANCE_SOURCE: &str = "provenance.source";
+/// Metadata key for the case identifier.
+pub const PROVENANCE_CASE: &str = "provenance.case";
+/// Metadata key for the tags.
+pub const PROVENANCE_TAGS: &str = "provenance.tags";
+/// Metadata key for the permission bits of the original file.
+pub const PROVENANCE_MODE: &str = "provenance.mode";
+/// Metadata key for the creation time of the original file.
+pub const PROVENANCE_CREATED: &str = "provenance.created";
+/// Metadata key for the modification time of the original file.
+pub const PROVENANCE_MODIFIED: &str = "provenance.modified";
+
+/// Where a sample came from.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Provenance {
+    /// The original file name.
+    pub filename: Option<String>,
+    /// The original path.
+    pub path: Option<String>,
+    /// When the sample was collected.
+    pub collected: Option<SystemTime>,
+    /// Who submitted the sample.
+    pub submitter: Option<String>,
+    /// The URL the sample came from.
+    pub source: Option<String>,
+    /// The case the sample belongs to.
+    pub case: Option<String>,
+    /// Free-form tags.  Tags cannot contain line breaks.
+    pub tags: Vec<String>,
+    /// The permission bits of the original file.  These are only known on
+    /// Unix.
+    pub mode: Option<u32>,
+    /// When the original file was created, if the platform records it.
+    pub created: Option<SystemTime>,
+    /// When the original file was last modified.
+    pub modified: Option<SystemTime>,
+}
+
+impl Provenance {
+    /// Create an empty provenance record.
+    pub fn new() -> Provenance {
+        Provenance::default()
+    }
+
+    /// Create a provenance record for a file being collected now.  The
+    /// file name, absolute path, permission bits, and timestamps are
+    /// recorded, along with the current time and the current user as the
+    /// submitter.
+    pub fn for_file<P: AsRef<Path>>(path: P) -> Provenance {
+        let path = path.as_ref();
+        let absolute = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let attributes = fs::metadata(path).ok();
+        Provenance {
+            filename: path.file_name().map(|name| name.to_string_lossy().into_owned()),
+            path: Some(absolute.to_string_lossy().into_owned()),
+            collected: Some(SystemTime::now()),
+            submitter: env::var("USER").or_else(|_| env::var("USERNAME")).ok(),
+            mode: attributes.as_ref().and_then(file_mode),
+            created: attributes.as_ref().and_then(|attributes| attributes.created().ok()),
+            modified: attributes.as_ref().and_then(|attributes| attributes.modified().ok()),
+            ..Provenance::default()
+        }
+    }
+
+    /// Get the original file name, if it is safe to use as the name of a
+    /// file in a folder.  The name comes from the encoded file, so it
+    /// cannot be trusted: names that are empty, refer to a folder, or
+    /// contain a path separator are rejected.
+    pub fn safe_filename(&self) -> Option<&str> {
+        match self.filename {
+            Some(ref name) if !name.is_empty() && name != "." && name != ".."
+                && !name.contains(['/', '\\', '\0']) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Store the provenance in the given metadata, replacing any existing
+    /// provenance.  The tags are stored one per line, so a tag containing
+    /// a line break is an error, and the metadata is left unchanged.
+    pub fn store(&self, metadata: &mut Metadata) -> Result<()> {
+        if let Some(tag) = self.tags.iter().find(|tag| tag.contains(['\n', '\r'])) {
+            return Err(PicoError::BadTag(tag.clone()));
+        }
+        let texts = [
+            (PROVENANCE_FILENAME, &self.filename),
+            (PROVENANCE_PATH, &self.path),
+            (PROVENANCE_SUBMITTER, &self.submitter),
+            (PROVENANCE_SOURCE, &self.source),
+            (PROVENANCE_CASE, &self.case),
+        ];
+        for &(key, value) in &texts {
+            match *value {
+                Some(ref text) => metadata.insert(key, MetadataValue::Text(text.clone())),
+                None => metadata.remove(key),
+            };
+        }
+        match self.collected {
+            Some(time) => metadata.insert(PROVENANCE_COLLECTED, MetadataValue::Timestamp(time)),
+            None => metadata.remove(PROVENANCE_COLLECTED),
+        };
+        if self.tags.is_empty() {
+            metadata.remove(PROVENANCE_TAGS);
+        } else {
+            metadata.insert(PROVENANCE_TAGS, MetadataValue::Text(self.tags.join("\n")));
+        }
+        match self.mode {
+            Some(mode) => metadata.insert(PROVENANCE_MODE, MetadataValue::Int(mode as i64)),
+            None => metadata.remove(PROVENANCE_MODE),
+        };
+        let times = [
+            (PROVENANCE_CREATED, self.created),
+            (PROVENANCE_MODIFIED, self.modified),
+        ];
+        for &(key, value) in &times {
+            match value {
+                Some(time) => metadata.insert(key, MetadataValue::Timestamp(time)),
+                None => metadata.remove(key),
+            };
+        }
+        Ok(())
+    }
+
+    /// Encode the provenance as structured metadata.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut metadata = Metadata::new();
+        self.store(&mut metadata)?;
+        metadata.encode()
+    }
+
+    /// Add the provenance to encoded metadata, and encode the result.  Any
+    /// other structured metadata is kept.  Legacy raw metadata is kept
+    /// under `LEGACY_KEY`.
+    pub fn merge(&self, metadata: &[u8]) -> Result<Vec<u8>> {
+        let mut metadata = Metadata::decode(metadata)?;
+        self.store(&mut metadata)?;
+        metadata.encode()
+    }
+
+    /// Get the provenance stored in the given metadata.  If no provenance
+    /// is stored, `None` is returned.  Values of the wrong type are
+    /// ignored.
+    pub fn from_metadata(metadata: &Metadata) -> Option<Provenance> {
+        let text = |key: &str| match metadata.get(key) {
+            Some(MetadataValue::Text(text)) => Some(text.clone()),
+            _ => None,
+        };
+        let time = |key: &str| match metadata.get(key) {
+            Some(&MetadataValue::Timestamp(time)) => Some(time),
+            _ => None,
+        };
+        let provenance = Provenance {
+            filename: text(PROVENANCE_FILENAME),
+            path: text(PROVENANCE_PATH),
+            collected: time(PROVENANCE_COLLECTED),
+            submitter: text(PROVENANCE_SUBMITTER),
+            source: text(PROVENANCE_SOURCE),
+            case: text(PROVENANCE_CASE),
+            tags: text(PROVENANCE_TAGS)
+                .map(|tags| tags.lines().map(|tag| tag.to_string()).collect())
+                .unwrap_or_default(),
+            mode: match metadata.get(PROVENANCE_MODE) {
+                Some(&MetadataValue::Int(mode)) if mode >= 0 && mode <= u32::MAX as i64 =>
+                    Some(mode as u32),
+                _ => None,
+            },
+            created: time(PROVENANCE_CREATED),
+            modified: time(PROVENANCE_MODIFIED),
+        };
+        if provenance == Provenance::default() {
+            None
+        } else {
+            Some(provenance)
+        }
+    }
+
+    /// Get the fields that are present, by name, with text values.  Times
+    /// are given in RFC 3339 form, in UTC, and the mode in octal.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        if let Some(ref value) = self.filename { fields.push(("filename", value.clone())); }
+        if let Some(ref value) = self.path { fields.push(("path", value.clone())); }
+        if let Some(time) = self.collected { fields.push(("collected", format_time(time))); }
+        if let Some(ref value) = self.submitter { fields.push(("submitter", value.clone())); }
+        if let Some(ref value) = self.source { fields.push(("source", value.clone())); }
+        if let Some(ref value) = self.case { fields.push(("case", value.clone())); }
+        if let Some(mode) = self.mode { fields.push(("mode", format!("{:04o}", mode))); }
+        if let Some(time) = self.created { fields.push(("created", format_time(time))); }
+        if let Some(time) = self.modified { fields.push(("modified", format_time(time))); }
+        fields
+    }
+
+    /// Dump the provenance as an entry of a header dump.  For the DICT,
+    /// JSON, and YAML formats this is a `provenance` entry of the
+    /// enclosing map, with a trailing comma for DICT and JSON.  For XML
+    /// this is a `provenance` element.
+    #[allow(unused_must_use)]
+    pub fn dump<U: Write>(&self, target: &mut U, form: &HeaderFormat) {
+        let fields = self.fields();
+        let tags: Vec<String> = self.tags.iter().map(|tag| quote(tag)).collect();
+        match *form {
+            HeaderFormat::DICT | HeaderFormat::JSON => {
+                let mut lines: Vec<String> = fields.iter()
+                    .map(|&(name, ref value)| format!("        \"{}\" : {}", name, quote(value)))
+                    .collect();
+                if !tags.is_empty() {
+                    lines.push(format!("        \"tags\" : [ {} ]", tags.join(", ")));
+                }
+                // JSON does not permit a trailing comma after the last line.
+                let separator = match *form {
+                    HeaderFormat::DICT => ",\n",
+                    _ => "\n",
+                };
+                writeln!(target, "    \"provenance\" : {{");
+                for (index, line) in lines.iter().enumerate() {
+                    let last = index + 1 == lines.len();
+                    write!(target, "{}{}", line, if last { separator } else { ",\n" });
+                }
+                writeln!(target, "    }},");
+            }
+            HeaderFormat::YAML => {
+                writeln!(target, "provenance:");
+                for (name, value) in fields {
+                    writeln!(target, "  {}: {}", name, quote(&value));
+                }
+                if !tags.is_empty() {
+                    writeln!(target, "  tags: [ {} ]", tags.join(", "));
+                }
+            }
+            HeaderFormat::XML => {
+                write!(target, "<provenance");
+                for (name, value) in fields {
+                    write!(target, " {}='{}'", name, xml_escape(&value));
+                }
+                if self.tags.is_empty() {
+                    write!(target, " />");
+                } else {
+                    write!(target, ">");
+                    for tag in &self.tags {
+                        write!(target, "<tag>{}</tag>", xml_escape(tag));
+                    }
+                    write!(target, "</provenance>");
+                }
+            }
+        }
+    }
+}
+
+/// Get the permission bits of a file.
+#[cfg(unix)]
+fn file_mode(attributes: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(attributes.permissions().mode() & 0o7777)
+}
+
+/// Get the permission bits of a file.  These are only known on Unix.
+#[cfg(not(unix))]
+fn file_mode(_attributes: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Quote a string for the DICT, JSON, and YAML formats, all of which
+/// accept JSON string syntax.
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in text.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Escape a string for use in XML text or a single-quoted attribute.
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+/// Format a time in RFC 3339 form, in UTC, to the second.
+fn format_time(time: SystemTime) -> String {
+    let seconds = match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(err) => {
+            // Round down to the earlier whole second.
+            let before = err.duration();
+            -(before.as_secs() as i64) - if before.subsec_nanos() > 0 { 1 } else { 0 }
+        }
+    };
+    let days = seconds.div_euclid(86400);
+    let of_day = seconds.rem_euclid(86400);
+
+    // Convert days since the epoch to a civil date.
+    let shifted = days + 719468;
+    let era = shifted.div_euclid(146097);
+    let of_era = shifted.rem_euclid(146097);
+    let year_of_era = (of_era - of_era / 1460 + of_era / 36524 - of_era / 146096) / 365;
+    let day_of_year = of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, of_day / 3600, of_day % 3600 / 60, of_day % 60
+    )
+}
+
+#[allow(unused_imports)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+    use header::HeaderFormat;
+    use errors::PicoError;
+    use metadata::Metadata;
+    use super::{Provenance, format_time};
+
+    #[test]
+    fn provenance_test_1() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(format_time(UNIX_EPOCH + Duration::new(951_782_400, 0)), "2000-02-29T00:00:00Z");
+        assert_eq!(format_time(UNIX_EPOCH + Duration::new(1_500_000_000, 5)), "2017-07-14T02:40:00Z");
+        assert_eq!(format_time(UNIX_EPOCH - Duration::new(1, 0)), "1969-12-31T23:59:59Z");
+        assert_eq!(format_time(UNIX_EPOCH - Duration::new(1, 5)), "1969-12-31T23:59:58Z");
+
+        let metadata = Metadata::new();
+        assert_eq!(Provenance::from_metadata(&metadata), None);
+    }
+
+    #[test]
+    fn provenance_test_2() {
+        let mut provenance = Provenance::new();
+        provenance.filename = Some("it's.exe".to_string());
+        provenance.collected = Some(UNIX_EPOCH + Duration::new(1_500_000_000, 0));
+        provenance.tags = vec!["a\"b".to_string(), "<c>".to_string()];
+        let mut output: Vec<u8> = Vec::new();
+        provenance.dump(&mut output, &HeaderFormat::JSON);
+        assert_eq!(String::from_utf8(output).unwrap(),
+            "    \"provenance\" : {\n        \"filename\" : \"it's.exe\",\n        \
+            \"collected\" : \"2017-07-14T02:40:00Z\",\n        \
+            \"tags\" : [ \"a\\\"b\", \"<c>\" ]\n    },\n");
+        let mut output: Vec<u8> = Vec::new();
+        provenance.dump(&mut output, &HeaderFormat::XML);
+        assert_eq!(String::from_utf8(output).unwrap(),
+            "<provenance filename='it&apos;s.exe' collected='2017-07-14T02:40:00Z'>\
+            <tag>a&quot;b</tag><tag>&lt;c&gt;</tag></provenance>");
+        let mut metadata = Metadata::new();
+        provenance.store(&mut metadata).unwrap();
+        assert_eq!(Provenance::from_metadata(&metadata), Some(provenance.clone()));
+
+        // Tags are stored one per line, so they cannot contain line breaks.
+        provenance.tags.push("d\re".to_string());
+        match provenance.store(&mut metadata) {
+            Err(PicoError::BadTag(ref tag)) if tag == "d\re" => (),
+            _ => panic!("Expected a bad tag."),
+        }
+    }
+
+    #[test]
+    fn provenance_test_3() {
+        let mut provenance = Provenance::new();
+        assert_eq!(provenance.safe_filename(), None);
+        for name in &["", ".", "..", "../evil", "a/b", "a\\b"] {
+            provenance.filename = Some(name.to_string());
+            assert_eq!(provenance.safe_filename(), None);
+        }
+        provenance.filename = Some("..sample.bin".to_string());
+        assert_eq!(provenance.safe_filename(), Some("..sample.bin"));
+
+        provenance.mode = Some(0o755);
+        provenance.modified = Some(UNIX_EPOCH + Duration::new(1_500_000_000, 5));
+        let mut output: Vec<u8> = Vec::new();
+        provenance.dump(&mut output, &HeaderFormat::YAML);
+        assert_eq!(String::from_utf8(output).unwrap(),
+            "provenance:\n  filename: \"..sample.bin\"\n  mode: \"0755\"\n  \
+            modified: \"2017-07-14T02:40:00Z\"\n");
+        let mut metadata = Metadata::new();
+        provenance.store(&mut metadata).unwrap();
+        assert_eq!(Provenance::from_metadata(&metadata), Some(provenance));
+    }
+}
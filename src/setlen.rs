@@ -0,0 +1,48 @@
+//! Truncate or extend the underlying storage of a Pico-encoded file.
+//!
+//! Operations that move the data, such as resizing the metadata, may need
+//! to shorten the file.  `Read`, `Write`, and `Seek` cannot do that, so
+//! those operations also require the file to implement `SetLen`.
+
+use std::fs::File;
+use std::io;
+use std::io::Cursor;
+
+/// Storage whose length can be set directly.
+pub trait SetLen {
+    /// Truncate or extend the storage to the given length in bytes.  Any
+    /// bytes added are zero.
+    fn set_len(&mut self, length: u64) -> io::Result<()>;
+}
+
+impl SetLen for File {
+    fn set_len(&mut self, length: u64) -> io::Result<()> {
+        File::set_len(self, length)
+    }
+}
+
+impl SetLen for &File {
+    fn set_len(&mut self, length: u64) -> io::Result<()> {
+        File::set_len(self, length)
+    }
+}
+
+impl SetLen for Cursor<Vec<u8>> {
+    fn set_len(&mut self, length: u64) -> io::Result<()> {
+        self.get_mut().resize(length as usize, 0);
+        Ok(())
+    }
+}
+
+impl SetLen for Cursor<&mut Vec<u8>> {
+    fn set_len(&mut self, length: u64) -> io::Result<()> {
+        self.get_mut().resize(length as usize, 0);
+        Ok(())
+    }
+}
+
+impl<T: SetLen + ?Sized> SetLen for &mut T {
+    fn set_len(&mut self, length: u64) -> io::Result<()> {
+        (**self).set_len(length)
+    }
+}
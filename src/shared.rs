@@ -0,0 +1,212 @@
+//! Concurrent read-only access to a Pico-encoded file.
+//!
+//! A `Pico` must seek its file for every read, so reading requires
+//! `&mut self`.  A `SharedPicoReader` instead uses positional reads, which
+//! do not move a shared file position, so every read method takes `&self`
+//! and a single reader can be shared among many threads.
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use std::sync::Arc;
+//! use std::thread;
+//! use pico::SharedPicoReader;
+//!
+//! let reader = Arc::new(SharedPicoReader::open(File::open("sample.pico").unwrap()).unwrap());
+//! let workers: Vec<_> = (0..4).map(|index| {
+//!     let reader = reader.clone();
+//!     thread::spawn(move || {
+//!         let mut buffer = [0u8; 4096];
+//!         reader.get(index * 4096, &mut buffer).unwrap()
+//!     })
+//! }).collect();
+//! for worker in workers {
+//!     worker.join().unwrap();
+//! }
+//! ```
+
+use std::fs::File;
+use std::io;
+use std::io::{Seek, SeekFrom};
+use header::PicoHeader;
+use options::PicoOpenOptions;
+use crypt::Cipher;
+use errors::{PicoError, Result};
+
+/// Read from a file at the given offset without using the file position.
+#[cfg(unix)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buffer, offset)
+}
+
+/// Read from a file at the given offset.  On Windows this moves the file
+/// position, but reads through a shared reader never depend on it.
+#[cfg(windows)]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buffer, offset)
+}
+
+/// Read from a file at the given offset.  Other platforms have no
+/// positional reads, so this seeks and then reads, holding a lock so that
+/// reads from different threads cannot interleave.
+#[cfg(not(any(unix, windows)))]
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::io::Read;
+    use std::sync::Mutex;
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+    file.read(buffer)
+}
+
+/// Read as much as possible, up to the length of the buffer, from a file
+/// at the given offset.  The number of bytes read is returned, which is
+/// less than the buffer length only at the end of the file.
+fn read_fully_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut count = 0;
+    while count < buffer.len() {
+        match read_at(file, &mut buffer[count..], offset + count as u64) {
+            Ok(0) => break,
+            Ok(num) => count += num,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(count)
+}
+
+/// Read-only access to a Pico-encoded file that can be shared among
+/// threads.  The file is never modified.
+pub struct SharedPicoReader {
+    /// The header.
+    header: PicoHeader,
+    /// The cipher used to decrypt data.
+    cipher: Box<dyn Cipher>,
+    /// The file, used only for positional reads.
+    file: File,
+}
+
+impl SharedPicoReader {
+    /// Initialize from an existing, open, Pico-encoded file, with the
+    /// default limits.  Only read access is needed.  Use `PicoOpenOptions`
+    /// to set the limits.
+    pub fn open(file: File) -> Result<SharedPicoReader> {
+        SharedPicoReader::open_with(file, &PicoOpenOptions::new())
+    }
+
+    /// Initialize from an existing, open, Pico-encoded file, applying the
+    /// given limits.
+    pub(crate) fn open_with(mut file: File, options: &PicoOpenOptions) -> Result<SharedPicoReader> {
+        file.seek(SeekFrom::Start(0)).map_err(
+            |err| PicoError::SeekFailed(3001, err),
+        )?;
+        let header = options.read_header(&mut file)?;
+        let length = file.metadata().map_err(
+            |err| PicoError::ReadFailed(3005, err),
+        )?.len();
+        header.check_length(length)?;
+        Ok(SharedPicoReader {
+            cipher: header.get_cipher()?.cipher(&header.key),
+            header,
+            file,
+        })
+    }
+
+    /// Get the header of this file.
+    pub fn get_header(&self) -> &PicoHeader {
+        &self.header
+    }
+
+    /// Get the number of data bytes stored in the file.
+    pub fn data_len(&self) -> Result<u64> {
+        let end = self.file.metadata().map_err(
+            |err| PicoError::ReadFailed(3002, err),
+        )?.len();
+        Ok(end.saturating_sub(self.header.offset as u64))
+    }
+
+    /// Obtain some part of the stored metadata.
+    ///
+    /// # Arguments
+    /// * `start`  - Zero-based start index within the metadata.
+    /// * `buffer` - The buffer to get the metadata.
+    ///
+    /// If possible, the buffer is filled.  The number of bytes read is
+    /// returned.
+    pub fn get_metadata(&self, start: u32, buffer: &mut [u8]) -> Result<usize> {
+        // If the offset is past the end of the metadata, then stop now.
+        let mdlen = self.header.md_length;
+        if start >= mdlen { return Ok(0); }
+        let max = ((mdlen - start) as usize).min(buffer.len());
+        let true_offset = self.header.md_start() as u64 + start as u64;
+        read_fully_at(&self.file, &mut buffer[0..max], true_offset).map_err(
+            |err| PicoError::ReadFailed(3003, err),
+        )
+    }
+
+    /// Get decrypted data from the file.
+    ///
+    /// # Arguments
+    /// * `position` - Zero-based index within the data.
+    /// * `buffer`   - The buffer to get the data.
+    ///
+    /// If possible, the buffer is filled.  The number of bytes read is
+    /// returned, which is less than the buffer length only at the end of
+    /// the data.
+    pub fn get(&self, position: usize, buffer: &mut [u8]) -> Result<usize> {
+        let true_offset = self.header.offset as u64 + position as u64;
+        let count = read_fully_at(&self.file, buffer, true_offset).map_err(
+            |err| PicoError::ReadFailed(3004, err),
+        )?;
+        self.cipher.apply(position, &mut buffer[0..count]);
+        Ok(count)
+    }
+
+    /// Get the underlying file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use std::fs::{File, create_dir_all, remove_file, write};
+    use std::sync::Arc;
+    use std::thread;
+    use super::SharedPicoReader;
+
+    #[test]
+    fn shared_test() {
+        fn is_sync<T: Sync + Send>() {}
+        is_sync::<SharedPicoReader>();
+
+        create_dir_all("_test").unwrap();
+        let text: Vec<u8> = (0..100_000usize).map(|index| (index * 31 % 251) as u8).collect();
+        let encoded = ::encode_bytes(&text, vec![0x55, 0x21, 0xe4], b"Newcomb").unwrap();
+        write("_test/shared_test.pico", &encoded).unwrap();
+        let reader = Arc::new(
+            SharedPicoReader::open(File::open("_test/shared_test.pico").unwrap()).unwrap());
+        assert_eq!(reader.data_len().unwrap(), text.len() as u64);
+        let mut metadata = [0u8; 10];
+        assert_eq!(reader.get_metadata(0, &mut metadata).unwrap(), 7);
+        assert_eq!(&metadata[0..7], b"Newcomb");
+
+        let workers: Vec<_> = (0..8).map(|index| {
+            let reader = reader.clone();
+            thread::spawn(move || {
+                let mut buffer = vec![0u8; 15000];
+                let count = reader.get(index * 13000, &mut buffer).unwrap();
+                buffer.truncate(count);
+                buffer
+            })
+        }).collect();
+        for (index, worker) in workers.into_iter().enumerate() {
+            let start = index * 13000;
+            let end = (start + 15000).min(text.len());
+            assert_eq!(worker.join().unwrap(), &text[start..end]);
+        }
+        remove_file("_test/shared_test.pico").unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+//! Standard I/O adapters over the data in a Pico-encoded file.
+//!
+//! A `PicoReader` implements `Read`, `BufRead`, and `Seek` over the
+//! decrypted data, and a `PicoWriter` implements `Write` and `Seek`,
+//! encrypting the data as it is written.  Positions are zero-based
+//! indices within the data, so the header and metadata are never visible
+//! through these adapters.
+//!
+//! ```
+//! use std::io::{copy, Cursor};
+//! use pico::{Pico, PicoReader, PicoWriter};
+//!
+//! let mut encoded = Cursor::new(Vec::new());
+//! {
+//!     let pico = Pico::new(&mut encoded, vec![0x55, 0x21], 0).unwrap();
+//!     let mut writer = PicoWriter::new(pico);
+//!     copy(&mut &b"Martindale"[..], &mut writer).unwrap();
+//!     writer.into_inner().flush().unwrap();
+//! }
+//! let mut reader = PicoReader::open(&mut encoded).unwrap();
+//! let mut decoded = Vec::new();
+//! copy(&mut reader, &mut decoded).unwrap();
+//! assert_eq!(decoded, b"Martindale");
+//! ```
+
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom, BufRead};
+use pico::Pico;
+use constants::CHUNK_SIZE;
+
+/// Compute a new position for a seek.
+///
+/// # Arguments
+/// * `current` - The current zero-based position within the data.
+/// * `length`  - The number of data bytes.
+/// * `pos`     - The requested seek.
+fn seek_position(current: u64, length: u64, pos: SeekFrom) -> io::Result<u64> {
+    let (base, delta) = match pos {
+        SeekFrom::Start(offset) => return Ok(offset),
+        SeekFrom::Current(delta) => (current, delta),
+        SeekFrom::End(delta) => (length, delta),
+    };
+    let target = if delta < 0 {
+        base.checked_sub(delta.unsigned_abs())
+    } else {
+        base.checked_add(delta as u64)
+    };
+    target.ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Invalid seek to a negative or overflowing position.",
+    ))
+}
+
+/// Read decrypted data from a Pico-encoded file.
+///
+/// The reader only requires `Read` and `Seek`, so it can be used on files
+/// opened without write access.
+pub struct PicoReader<T: Read + Seek> {
+    /// The Pico file being read.
+    pico: Pico<T>,
+    /// Zero-based position within the data.
+    position: u64,
+    /// Decrypted data buffered for `BufRead`.
+    buffer: Vec<u8>,
+    /// Index of the next unread byte in the buffer.
+    start: usize,
+    /// Index one past the last valid byte in the buffer.
+    end: usize,
+}
+
+impl<T: Read + Seek> PicoReader<T> {
+    /// Create a reader positioned at the start of the data.
+    pub fn new(pico: Pico<T>) -> PicoReader<T> {
+        PicoReader {
+            pico,
+            position: 0,
+            buffer: vec![0u8; CHUNK_SIZE],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Open an existing Pico-encoded file and create a reader for it.
+    pub fn open(file: T) -> ::errors::Result<PicoReader<T>> {
+        Ok(PicoReader::new(Pico::open(file)?))
+    }
+
+    /// Get a reference to the underlying Pico file.
+    pub fn get_ref(&self) -> &Pico<T> {
+        &self.pico
+    }
+
+    /// Get the underlying Pico file, discarding any buffered data.
+    pub fn into_inner(self) -> Pico<T> {
+        self.pico
+    }
+}
+
+impl<T: Read + Seek> Read for PicoReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Large reads with nothing buffered bypass the buffer.
+        if self.start >= self.end && buf.len() >= self.buffer.len() {
+            let count = self.pico.get(self.position as usize, buf)?;
+            self.position += count as u64;
+            return Ok(count);
+        }
+        let count = {
+            let available = self.fill_buf()?;
+            let count = available.len().min(buf.len());
+            buf[..count].copy_from_slice(&available[..count]);
+            count
+        };
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+impl<T: Read + Seek> BufRead for PicoReader<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.start >= self.end {
+            self.end = self.pico.get(self.position as usize, &mut self.buffer)?;
+            self.start = 0;
+        }
+        Ok(&self.buffer[self.start..self.end])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.end - self.start);
+        self.start += amt;
+        self.position += amt as u64;
+    }
+}
+
+impl<T: Read + Seek> Seek for PicoReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let length = self.pico.data_len()?;
+        self.position = seek_position(self.position, length, pos)?;
+        self.start = 0;
+        self.end = 0;
+        Ok(self.position)
+    }
+}
+
+/// Write data to a Pico-encoded file, encrypting it as it is written.
+///
+/// Flushing the writer flushes the Pico file, which computes the hash and
+/// rewrites the header.
+pub struct PicoWriter<T: Read + Write + Seek> {
+    /// The Pico file being written.
+    pico: Pico<T>,
+    /// Zero-based position within the data.
+    position: u64,
+    /// Scratch space, since data is encrypted in place.
+    scratch: Vec<u8>,
+}
+
+impl<T: Read + Write + Seek> PicoWriter<T> {
+    /// Create a writer positioned at the start of the data.
+    pub fn new(pico: Pico<T>) -> PicoWriter<T> {
+        PicoWriter {
+            pico,
+            position: 0,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Get a reference to the underlying Pico file.
+    pub fn get_ref(&self) -> &Pico<T> {
+        &self.pico
+    }
+
+    /// Get the underlying Pico file.  The Pico file is not flushed.
+    pub fn into_inner(self) -> Pico<T> {
+        self.pico
+    }
+}
+
+impl<T: Read + Write + Seek> Write for PicoWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.scratch.clear();
+        self.scratch.extend_from_slice(buf);
+        let count = self.pico.put(self.position as usize, &mut self.scratch)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pico.flush()?;
+        Ok(())
+    }
+}
+
+impl<T: Read + Write + Seek> Seek for PicoWriter<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let length = self.pico.data_len()?;
+        self.position = seek_position(self.position, length, pos)?;
+        Ok(self.position)
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use std::io::{copy, Cursor, Read, Write, Seek, SeekFrom, BufRead};
+    use pico::Pico;
+    use super::{PicoReader, PicoWriter};
+
+    #[test]
+    fn stream_test_1() {
+        let mut encoded = Cursor::new(Vec::new());
+        let text = b"Martindale\nNewcomb\n".repeat(500);
+        {
+            let pico = Pico::new(&mut encoded, vec![0x55, 0x21, 0xe4, 0x9a], 10).unwrap();
+            let mut writer = PicoWriter::new(pico);
+            copy(&mut &text[..], &mut writer).unwrap();
+            writer.flush().unwrap();
+        }
+        let mut reader = PicoReader::open(&mut encoded).unwrap();
+        let mut decoded = Vec::new();
+        copy(&mut reader, &mut decoded).unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn stream_test_2() {
+        let mut encoded = Cursor::new(Vec::new());
+        {
+            let pico = Pico::new(&mut encoded, vec![0x55, 0x21, 0xe4, 0x9a], 0).unwrap();
+            let mut writer = PicoWriter::new(pico);
+            writer.write_all(b"Martindale\nNewcomb\n").unwrap();
+            assert_eq!(writer.seek(SeekFrom::Start(6)).unwrap(), 6);
+            writer.write_all(b"Z").unwrap();
+            writer.flush().unwrap();
+        }
+        let mut reader = PicoReader::open(&mut encoded).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "MartinZale\n");
+        assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), 15);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "omb\n");
+        assert!(reader.seek(SeekFrom::Current(-20)).is_err());
+    }
+}